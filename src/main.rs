@@ -1,298 +1,5973 @@
 use clap::Parser;
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
 use std::path::{PathBuf, Path};
 use std::fs;
-use std::io::{self, BufReader, BufRead};
+use std::io::{self, BufReader, BufRead, BufWriter, Read, Write};
 use colored::Colorize;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::sync::{mpsc, Arc, Condvar, Mutex, Once};
+use notify::{EventKind, RecursiveMode, Watcher};
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
 
-#[derive(Parser, Debug)]
+/// Number of permission-denied paths listed before the rest are collapsed into a count.
+const PERMISSION_DENIED_PREVIEW: usize = 5;
+
+/// Guards the warning printed when creation times can't be read.
+static CREATED_UNSUPPORTED: Once = Once::new();
+
+/// Number of empty files whose content search was skipped, shared by the search threads.
+static ZERO_BYTE_SKIPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Directories listed and files checked against the criteria, shared by the search threads.
+static DIRS_SEARCHED: AtomicUsize = AtomicUsize::new(0);
+static FILES_CHECKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once a search thread stops at the `--deadline`.
+static DEADLINE_REACHED: AtomicBool = AtomicBool::new(false);
+
+/// Paths whose name is not valid UTF-8, collected with `--strict-utf8`.
+static NON_UTF8_PATHS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Name of the per-directory ignore files read during traversal.
+const IGNORE_FILE_NAME: &str = ".findfileignore";
+
+/// The ripgrep ignore files also read with `--use-ignore-files`, from lowest to highest precedence.
+const RIPGREP_IGNORE_FILE_NAMES: [&str; 2] = [".ignore", ".rgignore"];
+
+/// Directories skipped with `--ignore-vcs`: version control metadata and common build outputs.
+const VCS_DIRS: [&str; 6] = [".git", ".hg", ".svn", "target", "node_modules", ".venv"];
+
+/// Number of results that can be waiting for the printer before search threads block.
+const RESULT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Files and matches of a search root that can wait, with `--content-jobs`, for an earlier
+/// file of the same root to be searched before they are printed.
+const REORDER_CAPACITY: usize = 4096;
+
+/// Longest first line read by `--shebang`; kernels stop reading an interpreter line well before.
+const SHEBANG_MAX_LEN: u64 = 4096;
+
+/// Bytes read by `--has-bom`: the byte-order mark and enough text to tell a binary file.
+const BOM_SNIFF_LEN: u64 = 512;
+
+/// Bytes read once from the start of a file for the checks that sniff it: as many as `infer`
+/// looks at for `--mime`, which covers the `#!` line and the byte-order mark too.
+const SNIFF_LEN: u64 = 8192;
+
+/// Number of lines read between two checks of the `--timeout` deadline.
+const DEADLINE_CHECK_INTERVAL: usize = 256;
+
+/// How long the watched directories must stay quiet before changed files are matched.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(250);
+
+#[derive(Parser, Debug, Serialize)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(short, long, value_name = "PATTERN")]
     exclude: Option<String>,
 
-    #[arg(short, long, default_value_t = false)]
+    #[arg(long = "exclude-type", value_name = "f|d", value_enum, value_delimiter = ',', default_value = "f",
+          help = "Entry types the exclude pattern applies to: f filters files, d prunes directories (f,d for both)")]
+    #[serde(serialize_with = "serialize_value_enums")]
+    exclude_type: Vec<ExcludeType>,
+
+    #[arg(long = "force-include", value_name = "PATH|GLOB", action = clap::ArgAction::Append,
+          help = "Never leave out entries matching this name glob, or path glob if it has a /, whatever the exclude, hidden and ignore file rules; may be repeated")]
+    force_include: Vec<String>,
+
+    #[arg(short = 't', long = "type", value_name = "f|l|s|p|b|c", value_enum, value_delimiter = ',',
+          help = "Only match entries of these types: f regular file, l symlink, and on Unix s socket, p fifo, b block device, c char device")]
+    #[serde(serialize_with = "serialize_value_enums")]
+    file_type: Vec<EntryType>,
+
+    #[arg(long = "ignore-vcs", default_value_t = false,
+          help = "Skip version control and build directories: .git, .hg, .svn, target, node_modules, .venv")]
+    ignore_vcs: bool,
+
+    #[arg(long = "no-findfileignore", default_value_t = false,
+          help = "Don't read the .findfileignore files found in searched directories")]
+    no_findfileignore: bool,
+
+    #[arg(long = "use-ignore-files", default_value_t = false,
+          help = "Also read the .ignore and .rgignore files of ripgrep, with the same syntax as .findfileignore")]
+    use_ignore_files: bool,
+
+    #[arg(long = "broken-symlinks", default_value_t = false, conflicts_with = "valid_symlinks",
+          help = "Only match symlinks whose target doesn't exist")]
+    broken_symlinks: bool,
+
+    #[arg(long = "valid-symlinks", default_value_t = false,
+          help = "Only match symlinks whose target exists")]
+    valid_symlinks: bool,
+
+    #[arg(long = "links-to", value_name = "PATH",
+          help = "Only match symlinks whose resolved target is PATH or inside it, including symlinks to directories")]
+    links_to: Option<PathBuf>,
+
+    #[arg(long = "follow-only-top", default_value_t = false,
+          help = "Follow search roots that are symlinks, but not symlinked directories found below them (like find -H)")]
+    follow_only_top: bool,
+
+    #[arg(long = "same-filesystem", visible_alias = "xdev", default_value_t = false,
+          help = "Don't descend into directories on another filesystem than their search root, like find -xdev (Unix only)")]
+    same_filesystem: bool,
+
+    #[arg(long = "fs-type", value_name = "TYPE", value_delimiter = ',',
+          help = "Only descend into directories on filesystems of these types, e.g. ext4,xfs, read from /proc/self/mountinfo (Linux only)")]
+    fs_type: Vec<String>,
+
+    #[arg(short, long, default_value_t = false, help = "Include hidden entries: same as --hidden-files --hidden-dirs")]
     all: bool,
 
-    #[arg(short = 'f', long = "filter", value_name = "PATTERN", num_args = 1.., value_delimiter = ' ')]
+    #[arg(long = "hidden-files", default_value_t = false, help = "Match files whose name starts with a dot")]
+    hidden_files: bool,
+
+    #[arg(long = "hidden-dirs", default_value_t = false, help = "Descend into directories whose name starts with a dot")]
+    hidden_dirs: bool,
+
+    #[arg(short = 'f', long = "filter", value_name = "PATTERN", num_args = 1.., value_delimiter = ' ',
+          help = "Patterns matched against file names and, with --content, file contents (use --name for names only)")]
     filter: Vec<String>,
 
+    #[arg(long = "pattern", value_name = "PATTERN", action = clap::ArgAction::Append, allow_hyphen_values = true,
+          help = "A --filter pattern given whole, even when it starts with '-' or contains spaces; may be repeated")]
+    pattern: Vec<String>,
+
+    #[arg(long = "name", value_name = "GLOB", action = clap::ArgAction::Append, conflicts_with = "fuzzy",
+          help = "Only match files whose name matches this pattern, never applied to contents; may be repeated")]
+    name: Vec<String>,
+
+    #[arg(long = "name-regex", value_name = "REGEX", action = clap::ArgAction::Append, conflicts_with = "fuzzy",
+          help = "Match file names against a regular expression, used as is (not anchored); may be repeated")]
+    name_regex: Vec<String>,
+
+    #[arg(long = "name-glob", value_name = "GLOB", action = clap::ArgAction::Append, conflicts_with = "fuzzy",
+          help = "Match whole file names against a glob with *, ? and [...] classes; may be repeated")]
+    name_glob: Vec<String>,
+
+    #[arg(short = 'i', long = "ignore-case", default_value_t = false,
+          help = "Match filter, exclude, content and --path-contains patterns regardless of case")]
+    ignore_case: bool,
+
+    #[arg(long = "path-contains", value_name = "TEXT",
+          help = "Only match files whose full path contains TEXT, taken literally")]
+    path_contains: Option<String>,
+
+    #[arg(long = "ascii-fold", default_value_t = false,
+          help = "Ignore accents when matching file names, so cafe matches café (best effort)")]
+    ascii_fold: bool,
+
+    #[arg(long, default_value_t = false,
+          help = "Match file names fuzzily: the letters of a filter pattern must appear in order, case-insensitively")]
+    fuzzy: bool,
+
     #[arg(short = 'd', long, value_name = "DIRECTORY", action = clap::ArgAction::Append)]
     dir: Vec<String>,
 
     #[arg(value_name = "DIRECTORY", num_args = 0..)]
     additional_dirs: Vec<PathBuf>,
 
+    #[arg(long = "paths-from", value_name = "FILE", conflicts_with_all = ["dir", "additional_dirs", "watch"],
+          help = "Check only the file paths listed in FILE (one per line, - for stdin) instead of walking directories")]
+    paths_from: Option<PathBuf>,
+
     #[arg(short = 'c', long = "content", help = "Search for content within files")]
     content: bool,
 
+    #[arg(long = "content-not", value_name = "PATTERN", action = clap::ArgAction::Append, requires = "content",
+          help = "With --content, only report files in which no line matches PATTERN")]
+    content_not: Vec<String>,
+
+    #[arg(long = "content-exclude", value_name = "REGEX", action = clap::ArgAction::Append, requires = "content",
+          help = "With --content, ignore the lines matching this regular expression, e.g. '^\\s*//' for comment lines")]
+    content_exclude: Vec<String>,
+
+    #[arg(long, value_names = ["PATTERN", "REPLACEMENT"], num_args = 2, requires = "content",
+          help = "With --content, rewrite matching files in place, replacing every match of the regex PATTERN")]
+    replace: Vec<String>,
+
+    #[arg(long, default_value_t = false, requires = "replace",
+          help = "With --replace, keep a copy of each rewritten file with a .bak suffix")]
+    backup: bool,
+
+    #[arg(long, default_value_t = false, requires = "replace", conflicts_with = "backup",
+          help = "With --replace, print a unified diff of the changes to each file instead of rewriting it")]
+    diff: bool,
+
+    #[arg(long, default_value_t = false,
+          help = "Process the contents of each subdirectory before the entries next to it (post-order, like find -depth)")]
+    depth: bool,
+
+    #[arg(long = "filter-by-depth-range", visible_alias = "depth-range", value_name = "RANGE", conflicts_with = "paths_from",
+          help = "Only report entries whose depth below the search root is in RANGE (a..b, a..=b, a.. or ..=b; 1 is the root's own entries)")]
+    depth_range: Option<String>,
+
+    #[arg(long = "sorted-traversal", default_value_t = false,
+          help = "Visit the entries of each directory sorted by name, so the walk and its output are the same on every run")]
+    sorted_traversal: bool,
+
     #[arg(short = 'p', long = "Parameter-show", default_value_t = false)]
     parameter_show: bool,
+
+    #[arg(long = "show-config-json", default_value_t = false,
+          help = "Print every option in effect, defaults included, and the search directories as JSON, then exit without searching")]
+    show_config_json: bool,
+
+    #[arg(short = 'o', long, value_name = "FILE",
+          help = "Write the results to FILE instead of stdout (colors are disabled)")]
+    output: Option<PathBuf>,
+
+    #[arg(long, default_value_t = false, requires = "output",
+          help = "With --output, append to FILE instead of overwriting it")]
+    append: bool,
+
+    #[arg(long = "max-output-bytes", value_name = "N",
+          help = "Stop printing the text results once N bytes have been written, with a notice that the output was truncated")]
+    max_output_bytes: Option<u64>,
+
+    #[arg(long = "output-errors", default_value_t = false, requires = "output",
+          help = "With --output, also write errors to FILE instead of stderr")]
+    output_errors: bool,
+
+    #[arg(long = "json-report", value_name = "FILE",
+          help = "Also write the results as a JSON report to FILE, whatever the --format of the main output")]
+    json_report: Option<PathBuf>,
+
+    #[arg(long = "export-matches", value_name = "FILE", requires = "content", conflicts_with = "watch",
+          help = "Also write every matching line, with its path and line number, to FILE, as JSON, CSV or text after its .json, .csv or .txt extension")]
+    export_matches: Option<PathBuf>,
+
+    #[arg(long = "separate-stderr-summary", value_name = "FILE", num_args = 0..=1, conflicts_with = "output_errors",
+          help = "Print the error summary after everything else, behind a --- line, or write it to FILE")]
+    separate_stderr_summary: Option<Option<PathBuf>>,
+
+    #[arg(long = "max-error-entries", value_name = "K",
+          help = "List at most K permission-denied directories, in JSON (with a total count) and text output alike")]
+    max_error_entries: Option<usize>,
+
+    #[arg(long, value_enum, default_value = "text",
+          help = "Output format: text, json (a single document, schema version 1), jsonl to stream one JSON object per match as it is found, jsonl-full to add every piece of metadata to these objects, or html for a standalone page")]
+    #[serde(serialize_with = "serialize_value_enum")]
+    format: OutputFormat,
+
+    #[arg(long = "modified-on", value_name = "YYYY-MM-DD", value_parser = parse_date,
+          help = "Only match files last modified on this calendar day, in local time")]
+    #[serde(serialize_with = "serialize_as_debug")]
+    modified_on: Option<chrono::NaiveDate>,
+
+    #[arg(long = "accessed-within", value_name = "DURATION", value_parser = parse_duration,
+          help = "Only match files accessed within DURATION (e.g. 30m, 12h, 7d); unreliable on noatime mounts")]
+    #[serde(serialize_with = "serialize_as_debug")]
+    accessed_within: Option<Duration>,
+
+    #[arg(long = "created-within", value_name = "DURATION", value_parser = parse_duration,
+          help = "Only match files created within DURATION, where the filesystem records creation times")]
+    #[serde(serialize_with = "serialize_as_debug")]
+    created_within: Option<Duration>,
+
+    #[arg(long = "created-fallback-mtime", default_value_t = false, requires = "created_within",
+          help = "With --created-within, use the modification time when the creation time is unavailable")]
+    created_fallback_mtime: bool,
+
+    #[arg(long = "name-longer-than", value_name = "N",
+          help = "Only match files whose name is longer than N characters")]
+    name_longer_than: Option<usize>,
+
+    #[arg(long = "name-shorter-than", value_name = "N",
+          help = "Only match files whose name is shorter than N characters")]
+    name_shorter_than: Option<usize>,
+
+    #[arg(long = "path-components", value_name = "N",
+          help = "Only match files whose reported path has exactly N named components")]
+    path_components: Option<usize>,
+
+    #[arg(long = "count-lines", default_value_t = false, requires = "content",
+          help = "With --content, print path:N with the number of matching lines in each file")]
+    count_lines: bool,
+
+    #[arg(long = "total-matches", default_value_t = false, requires = "content",
+          help = "With --content, also print the total number of matching lines across all files")]
+    total_matches: bool,
+
+    #[arg(long = "min-matches", value_name = "N", requires = "content",
+          help = "With --content, only match files with at least N matching lines")]
+    min_matches: Option<usize>,
+
+    #[arg(long = "min-match-ratio", value_name = "R", requires = "content", value_parser = parse_ratio,
+          help = "With --content, only match files in which at least this fraction (0.0 to 1.0) of the lines match (reads each candidate file to the end)")]
+    min_match_ratio: Option<f64>,
+
+    #[arg(long = "dir-hit", default_value_t = false, requires = "content", conflicts_with_all = ["paths_from", "watch"],
+          help = "With --content, report directories holding a matching file instead of the files, reading no more files in a directory once one matches")]
+    dir_hit: bool,
+
+    #[arg(long = "read-buffer-size", value_name = "SIZE", value_parser = parse_buffer_size,
+          help = "Read buffer used when searching file contents (e.g. 64K, 1M; default 8K)")]
+    read_buffer_size: Option<usize>,
+
+    #[arg(long = "deadline", value_name = "DURATION", value_parser = parse_duration,
+          help = "Stop the whole search after this long (e.g. 30s, 5m) and report the matches found so far")]
+    #[serde(serialize_with = "serialize_as_debug")]
+    deadline: Option<Duration>,
+
+    #[arg(long = "timeout", value_name = "DURATION", value_parser = parse_duration, requires = "content",
+          help = "Abandon the content search of a file after this long (e.g. 500ms, 10s) and report it as timed out")]
+    #[serde(serialize_with = "serialize_as_debug")]
+    timeout: Option<Duration>,
+
+    #[arg(long = "content-skip-hidden", visible_alias = "ignore-hidden-content", default_value_t = false, requires = "content",
+          help = "With --content, never search the contents of dotfiles, even when --all or --hidden-files lists them")]
+    content_skip_hidden: bool,
+
+    #[arg(long = "include-zero-byte", default_value_t = false,
+          help = "Open empty files during a content search instead of skipping them")]
+    include_zero_byte: bool,
+
+    #[arg(long = "leaf-dirs", default_value_t = false, conflicts_with_all = ["content", "paths_from", "watch"],
+          help = "Report the directories that have no subdirectory, instead of files; name patterns apply to directory names")]
+    leaf_dirs: bool,
+
+    #[arg(long = "contains-file", value_name = "GLOB",
+          conflicts_with_all = ["content", "paths_from", "watch", "leaf_dirs", "search_archives"],
+          help = "Report the directories directly holding a file whose name matches GLOB (e.g. Cargo.toml), instead of files; name patterns apply to directory names")]
+    contains_file: Option<String>,
+
+    #[arg(long = "min-lines", value_name = "N",
+          help = "Only match text files with at least N lines (reads every candidate file)")]
+    min_lines: Option<usize>,
+
+    #[arg(long = "max-lines", value_name = "N",
+          help = "Only match text files with at most N lines (reads every candidate file)")]
+    max_lines: Option<usize>,
+
+    #[arg(long = "skip-larger-than", value_name = "SIZE", value_parser = parse_size,
+          help = "Leave out files larger than SIZE (e.g. 500K, 10M, 1G); their content is never read")]
+    skip_larger_than: Option<u64>,
+
+    #[arg(long, value_name = "TYPE",
+          help = "Only match files whose content looks like this MIME type, e.g. image/png or image/*")]
+    mime: Option<String>,
+
+    #[arg(long, value_name = "REGEX",
+          help = "Only match scripts whose #! first line matches this regular expression, e.g. python or ^#!/bin/sh$")]
+    shebang: Option<String>,
+
+    #[arg(long = "has-bom", default_value_t = false,
+          help = "Only match text files starting with a UTF-8 or UTF-16 byte-order mark")]
+    has_bom: bool,
+
+    #[arg(long = "lang", value_name = "LANGUAGE", value_delimiter = ',',
+          help = "Only match files in one of these programming languages, detected from the extension or the #! line, e.g. rust,python")]
+    lang: Vec<String>,
+
+    #[arg(long = "search-archives", default_value_t = false, conflicts_with_all = ["replace", "dir_hit", "leaf_dirs"],
+          help = "Also match the members of .zip archives by name (and with --content, by content), reported as archive.zip::member/path")]
+    search_archives: bool,
+
+    #[arg(long = "audit-perms", default_value_t = false,
+          help = "Only report files with unsafe permissions (world-writable, setuid, setgid), grouped by issue (Unix only)")]
+    audit_perms: bool,
+
+    #[arg(long, default_value_t = false,
+          help = "Only match files owned by the current (effective) user (Unix only)")]
+    mine: bool,
+
+    #[arg(long, value_name = "NAME[=VALUE]",
+          help = "Only match files carrying the extended attribute NAME, optionally with the given VALUE (Unix only)")]
+    xattr: Option<String>,
+
+    #[arg(long = "attr", value_name = "ATTRIBUTE", value_enum, value_delimiter = ',',
+          help = "Only match files carrying all of these Windows file attributes (Windows only)")]
+    #[serde(serialize_with = "serialize_value_enums")]
+    attr: Vec<FileAttribute>,
+
+    #[arg(long = "max-matches-per-file", value_name = "N", requires = "content",
+          help = "With --content, stop reading a file after N matching lines")]
+    max_matches_per_file: Option<usize>,
+
+    #[arg(long = "code-only", default_value_t = false,
+          help = "With --content, ignore line comments (// or #) in recognized source files")]
+    code_only: bool,
+
+    #[arg(long, default_value_t = false,
+          help = "Report each file only once, even when reached through overlapping directories or symlinks")]
+    unique: bool,
+
+    #[arg(long = "skip-seen", default_value_t = false,
+          help = "Don't report a file again under a later search root once it was reported under an earlier one")]
+    skip_seen: bool,
+
+    #[arg(long = "dedupe-by-name", default_value_t = false,
+          help = "Report only the first file with each name, listing the other places the name occurs in a separate section")]
+    dedupe_by_name: bool,
+
+    #[arg(short = 'v', long, action = clap::ArgAction::Count,
+          help = "Show more error detail: -v lists every permission error, -vv also shows the error kind")]
+    verbose: u8,
+
+    #[arg(long, default_value_t = false, overrides_with = "no_cache",
+          help = "Reuse directory listings cached by earlier runs for directories whose mtime is unchanged")]
+    cache: bool,
+
+    #[arg(long = "no-cache", default_value_t = false, overrides_with = "cache", help = "Don't use the directory listing cache")]
+    no_cache: bool,
+
+    #[arg(long = "clear-cache", default_value_t = false, help = "Delete all cached directory listings before searching")]
+    clear_cache: bool,
+
+    #[arg(long = "since-last-run", default_value_t = false, conflicts_with = "paths_from",
+          help = "Only report files modified since the last successful run with the same arguments")]
+    since_last_run: bool,
+
+    #[arg(long = "reset-last-run", default_value_t = false,
+          help = "Forget the time of the last run with these arguments before searching")]
+    reset_last_run: bool,
+
+    #[arg(long, value_name = "N", default_value_t = default_threads(),
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+          help = "Number of search roots walked at the same time, each by a single thread (defaults to the number of CPUs); see --content-jobs")]
+    threads: usize,
+
+    #[arg(long = "content-jobs", value_name = "N", requires = "content", conflicts_with = "dir_hit",
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+          help = "With --content, search the contents of N files at the same time, still reporting them in the order a single thread finds them")]
+    content_jobs: Option<usize>,
+
+    #[arg(long = "io-concurrency", value_name = "N",
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+          help = "Maximum number of directory listings and file checks running at the same time, across all --threads")]
+    io_concurrency: Option<usize>,
+
+    #[arg(long, value_enum, value_name = "KEY",
+          help = "Sort the results by name, path, size, mtime, score (best fuzzy matches first, with --fuzzy) or matches (most matching lines first) instead of reporting them in traversal order")]
+    #[serde(serialize_with = "serialize_optional_value_enum")]
+    sort: Option<SortKey>,
+
+    #[arg(long = "top-largest", value_name = "N", conflicts_with = "sort",
+          help = "Only report the N largest matching files, largest first")]
+    top_largest: Option<usize>,
+
+    #[arg(long = "sample", value_name = "N",
+          conflicts_with_all = ["top_largest", "unique", "skip_seen", "dedupe_by_name", "max_per_ext", "watch"],
+          help = "Only report a random sample of N matches, drawn without keeping every match in memory")]
+    sample: Option<usize>,
+
+    #[arg(long = "seed", value_name = "SEED", requires = "sample",
+          help = "With --sample, seed the random choice so that the same search gives the same sample")]
+    seed: Option<u64>,
+
+    #[arg(long = "max-per-ext", value_name = "N",
+          help = "Report at most N matches of each file extension, the first ones in output order")]
+    max_per_ext: Option<usize>,
+
+    #[arg(long = "format-template", value_name = "TEMPLATE", conflicts_with = "shell_quote",
+          help = "Print each match with a template: {path}, {name}, and {N} for capture group N of the matching content line")]
+    format_template: Option<String>,
+
+    #[arg(long = "only-matching", default_value_t = false, requires = "content", conflicts_with_all = ["count_lines", "shell_quote", "dir_hit"],
+          help = "Print every part of a line matched by a content pattern, one per line, instead of the file paths (like grep -o)")]
+    only_matching: bool,
+
+    #[arg(long = "shell-quote", default_value_t = false, conflicts_with = "count_lines",
+          help = "Print only the matching paths, one per line, quoted for a POSIX shell")]
+    shell_quote: bool,
+
+    #[arg(long, default_value_t = false, conflicts_with_all = ["count_lines", "shell_quote", "format_template", "only_matching"],
+          help = "Print only the matching paths, one per line, as percent-encoded file:// URIs of their absolute paths")]
+    uri: bool,
+
+    #[arg(long = "cwd-relative", default_value_t = false,
+          help = "Print matches relative to the current directory, e.g. ../other/file, whatever root they were found under")]
+    cwd_relative: bool,
+
+    #[arg(long = "names-only", default_value_t = false, conflicts_with_all = ["stems_only", "watch", "age_histogram", "count_by_dir"],
+          help = "Print only the file name of each match, without its directory; with --unique, repeated names are printed once")]
+    names_only: bool,
+
+    #[arg(long = "stems-only", default_value_t = false, conflicts_with_all = ["watch", "age_histogram", "count_by_dir"],
+          help = "Print only the file name of each match without its directory and extension; with --unique, repeated stems are printed once")]
+    stems_only: bool,
+
+    #[arg(long, default_value_t = false,
+          help = "Show who last changed each match according to git: the first matching line with --content, the file otherwise")]
+    blame: bool,
+
+    #[arg(long = "strict-utf8", default_value_t = false,
+          help = "Fail with exit code 2, printing no results, if any file or directory name met is not valid UTF-8")]
+    strict_utf8: bool,
+
+    #[arg(long = "no-summary", default_value_t = false, conflicts_with_all = ["audit_perms", "count_by_dir", "by_ext", "age_histogram", "depth_histogram", "stats", "dedupe_by_name", "total_matches"],
+          help = "Print only the matching paths, one per line, without the headers and trailers of the text output")]
+    no_summary: bool,
+
+    #[arg(long = "prune-empty", default_value_t = false,
+          help = "Print nothing but errors when no file matches")]
+    prune_empty: bool,
+
+    /// Maintainer option for profiling: walk and stat every entry once before the real
+    /// search, so that `--stats` measures matching on a warm OS cache.
+    #[arg(long, default_value_t = false, hide = true)]
+    warm: bool,
+
+    #[arg(long = "by-ext", default_value_t = false,
+          help = "After the results, print how many matches there are per file extension, most frequent first")]
+    by_ext: bool,
+
+    #[arg(long = "age-histogram", default_value_t = false,
+          help = "After the results, print how many matches were last modified less than 1 day, 1-7 days, 7-30 days and over 30 days ago")]
+    age_histogram: bool,
+
+    #[arg(long = "depth-histogram", default_value_t = false, conflicts_with = "paths_from",
+          help = "After the results, print how many matches there are at each depth below their search root")]
+    depth_histogram: bool,
+
+    #[arg(long = "count-by-dir", default_value_t = false, conflicts_with = "audit_perms",
+          help = "Instead of listing the results, print how many matches each directory holds directly, most first")]
+    count_by_dir: bool,
+
+    #[arg(long, default_value_t = false, help = "Print statistics about the search")]
+    stats: bool,
+
+    #[arg(long = "stats-machine", default_value_t = false,
+          help = "Print a single line dirs=N files=M matched=K elapsed_ms=T on stderr once the search is done")]
+    stats_machine: bool,
+
+    #[arg(long, default_value_t = false,
+          help = "After the search, keep watching the directories and print files that match as they are created or modified")]
+    watch: bool,
+
+    #[arg(long = "exec-dir", value_name = "COMMAND", conflicts_with = "watch",
+          help = "After the search, run this shell command once per directory holding a match, {} being the directory; end it with + to run it once with all of them (Unix only)")]
+    exec_dir: Option<String>,
 }
 
-fn main() {
-    let args = Args::parse();
-    
-    let filter_regexes: Vec<Regex> = args.filter
-        .iter()
-        .filter_map(|pattern| Regex::new(&format!("^{}$", pattern.replace("*", ".*"))).ok())
-        .collect();
+/// A file that satisfied the search criteria.
+#[derive(Debug, Clone, Default)]
+struct SearchResult {
+    path: String,
+    /// Number of matching lines, when they were counted with `--count-lines`.
+    matching_lines: Option<usize>,
+    /// Whether reading stopped at `--max-matches-per-file`, so the count is a lower bound.
+    truncated: bool,
+    /// Capture groups of the first matching content line, when `--format-template` uses them.
+    captures: Vec<Option<String>>,
+    /// Capture groups of every match in the content, `0` being the matched text, with `--only-matching`.
+    spans: Vec<Vec<Option<String>>>,
+    /// Best fuzzy match score of the file name, with `--fuzzy`.
+    score: Option<u32>,
+    /// Unsafe permissions found with `--audit-perms`.
+    perm_issues: Vec<PermIssue>,
+    /// Unified diff of the changes `--replace` would make, with `--diff`.
+    diff: Option<String>,
+    /// Size in bytes, read with `--top-largest`.
+    size: Option<u64>,
+    /// Line number of the first matching content line.
+    first_line: Option<usize>,
+    /// Last author and commit of the match according to git, with `--blame`.
+    blame: Option<Blame>,
+    /// Line numbers of every matching content line, with `--format jsonl-full` or `--export-matches`.
+    line_numbers: Vec<usize>,
+    /// Text of the lines in `line_numbers`, with `--export-matches`.
+    matched_lines: Vec<String>,
+    /// Metadata gathered for `--format jsonl-full`.
+    details: Option<FileDetails>,
+    /// Depth below the search root, where the entries of the root are at depth 1, with `--depth-histogram`.
+    depth: Option<usize>,
+}
+
+/// Metadata of a match that only `--format jsonl-full` reports.
+#[derive(Debug, Clone, Default, Serialize)]
+struct FileDetails {
+    /// `file`, `dir`, `symlink`, `socket`, `fifo`, `block` or `char`, of the entry itself.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    file_type: Option<&'static str>,
+    /// Permission bits in octal, such as `0644` (Unix only).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    permissions: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    readonly: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gid: Option<u32>,
+    /// Name of the owning user, when it can be looked up (Unix only).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+}
+
+/// Author and abbreviated hash of the commit that last changed a file or a line.
+#[derive(Debug, Clone, Serialize)]
+struct Blame {
+    author: String,
+    commit: String,
+}
+
+/// Potentially unsafe permission reported by `--audit-perms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PermIssue {
+    /// Anyone can modify the file.
+    WorldWritable,
+    /// The file runs with the privileges of its owner.
+    Setuid,
+    /// The file runs with the privileges of its group.
+    Setgid,
+}
+
+impl PermIssue {
+    /// Every issue, in the order their sections are printed.
+    const ALL: [PermIssue; 3] = [PermIssue::WorldWritable, PermIssue::Setuid, PermIssue::Setgid];
+
+    /// Title of the section listing the files with this issue.
+    fn label(self) -> &'static str {
+        match self {
+            PermIssue::WorldWritable => "World-writable",
+            PermIssue::Setuid => "Setuid",
+            PermIssue::Setgid => "Setgid",
+        }
+    }
+}
+
+/// Outcome of searching the content of a file.
+#[derive(Debug, Default)]
+struct ContentMatch {
+    /// Whether the content satisfies the positive and negated patterns.
+    matched: bool,
+    /// Number of lines matching a positive pattern; only exact when every line was scanned.
+    matching_lines: usize,
+    /// Whether counting stopped at the maximum number of matches.
+    truncated: bool,
+    /// Total number of lines, when requested and the file is not binary.
+    total_lines: Option<usize>,
+    /// Capture groups of the first line matching a positive pattern, when requested.
+    captures: Vec<Option<String>>,
+    /// Capture groups of every match of the span patterns on a matching line, in order.
+    spans: Vec<Vec<Option<String>>>,
+    /// Whether the search was abandoned at its deadline before reaching the end of the file.
+    timed_out: bool,
+    /// Line numbers of the lines counted in `matching_lines`, starting at 1.
+    line_numbers: Vec<usize>,
+    /// Text of the lines in `line_numbers`, when requested.
+    lines: Vec<String>,
+}
+
+/// Mutable state carried through the traversal of one search root.
+struct SearchContext {
+    /// Index of the search root, attached to every match.
+    root: usize,
+    /// Channel to the thread printing the results.
+    results: mpsc::SyncSender<SearchEvent>,
+    /// Directory listing cache, when `--cache` is set.
+    cache: Option<DirCache>,
+    /// Only stat the entries, without matching them, for `--warm`.
+    warm_only: bool,
+    /// The ignore files of the directories being traversed, outermost and lowest precedence first.
+    ignores: Vec<IgnoreFile>,
+    /// Permits for filesystem operations, shared by the search threads with `--io-concurrency`.
+    io_limit: Option<Arc<IoLimiter>>,
+    /// Device of the search root, with `--same-filesystem`.
+    root_device: Option<u64>,
+    /// When the whole search has to stop, with `--deadline`.
+    deadline: Option<Instant>,
+    /// Whether the filesystem of each device seen so far is one of the `--fs-type` types.
+    fs_type_allowed: HashMap<u64, bool>,
+    /// Depth of the directory being searched below the root, which is at depth 0.
+    depth: usize,
+    /// Gather the metadata of every match, for `--format jsonl-full`.
+    file_details: bool,
+    /// Canonical targets of the directory links followed to reach the directory being searched.
+    link_targets: Vec<PathBuf>,
+    /// Whether the directory being searched was pruned, and is only walked to reach the
+    /// `--force-include` paths below it.
+    pruned: bool,
+    /// The `--content-jobs` workers that the files of this root are handed to.
+    content_pool: Option<ContentPool>,
+    /// Position of the next file or match in the traversal order, with `--content-jobs`.
+    next_seq: usize,
+}
+
+/// The `--content-jobs` workers, as seen from a search thread.
+#[derive(Clone)]
+struct ContentPool {
+    jobs: mpsc::SyncSender<ContentJob>,
+    /// One slot per file or match not printed yet, given back by the printer.
+    reorder_slots: Arc<IoLimiter>,
+}
+
+/// A file handed to a `--content-jobs` worker, with its place in the traversal order.
+struct ContentJob {
+    root: usize,
+    seq: usize,
+    path: PathBuf,
+    /// Depth of the file below its root, with `--depth-histogram`.
+    depth: Option<usize>,
+    /// Whether to search the members of `.zip` archives too.
+    archives: bool,
+}
+
+/// Counting semaphore bounding the filesystem operations in flight.
+#[derive(Debug)]
+struct IoLimiter {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A permit from an `IoLimiter`, given back when dropped.
+struct IoPermit<'a> {
+    limiter: &'a IoLimiter,
+}
+
+impl IoLimiter {
+    fn new(permits: usize) -> IoLimiter {
+        IoLimiter { available: Mutex::new(permits), released: Condvar::new() }
+    }
+
+    /// Waits until a permit is available and takes it.
+    fn acquire(&self) -> IoPermit<'_> {
+        self.take();
+        IoPermit { limiter: self }
+    }
+
+    /// Waits until a permit is available and takes it, for a permit that another thread
+    /// gives back with `give_back`.
+    fn take(&self) {
+        // A poisoned count is still a valid count
+        let mut available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        while *available == 0 {
+            available = self.released.wait(available).unwrap_or_else(|e| e.into_inner());
+        }
+        *available -= 1;
+    }
+
+    /// Gives back a permit taken with `take`.
+    fn give_back(&self) {
+        *self.available.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.released.notify_one();
+    }
+}
+
+impl Drop for IoPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.give_back();
+    }
+}
+
+/// A rule read from a `.findfileignore` file, or a `.ignore` or `.rgignore` one.
+#[derive(Debug)]
+struct IgnoreRule {
+    /// The compiled glob.
+    regex: Regex,
+    /// `!pattern`: re-includes entries excluded by an earlier rule.
+    negated: bool,
+    /// `pattern/`: only applies to directories.
+    dir_only: bool,
+    /// Patterns containing a `/` match the path relative to the ignore file, others the entry name.
+    anchored: bool,
+}
+
+/// The rules of one ignore file, applying to the directory holding it.
+#[derive(Debug)]
+struct IgnoreFile {
+    base: PathBuf,
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreFile {
+    /// Reads an ignore file of a directory, if there is one.
+    ///
+    /// Blank lines and lines starting with `#` are skipped. In patterns, `*` matches within
+    /// a path component, `**` across components and `?` a single character.
+    ///
+    /// # Parameters
+    ///
+    /// * `dir` - A reference to the `Path` of the directory.
+    /// * `file_name` - The name of the ignore file, such as `.findfileignore`.
+    ///
+    /// # Returns
+    ///
+    /// The parsed rules, or `None` if the directory has no such file or it can't be read.
+    fn load(dir: &Path, file_name: &str) -> Option<IgnoreFile> {
+        let text = fs::read_to_string(dir.join(file_name)).ok()?;
+        let rules = text.lines().filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let anchored = line.contains('/');
+            let regex = ignore_glob_regex(line.trim_start_matches('/'))?;
+            Some(IgnoreRule { regex, negated, dir_only, anchored })
+        }).collect();
+        Some(IgnoreFile { base: dir.to_path_buf(), rules })
+    }
+}
+
+impl SearchContext {
+    /// Checks an entry against the ignore rules in effect.
+    ///
+    /// Like `.gitignore`, rules from deeper directories and later lines take precedence. In a
+    /// given directory, `.rgignore` overrides `.ignore`, and `.findfileignore` overrides both.
+    ///
+    /// # Parameters
+    ///
+    /// * `path` - A reference to the `Path` of the entry.
+    /// * `is_dir` - Whether the entry is a directory.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the last matching rule excludes the entry, `false` otherwise.
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            return false;
+        };
+        let mut ignored = false;
+        for file in &self.ignores {
+            let Ok(relative) = path.strip_prefix(&file.base) else { continue };
+            let relative: Vec<&str> = relative.components().filter_map(|c| c.as_os_str().to_str()).collect();
+            let relative = relative.join("/");
+            for rule in &file.rules {
+                let candidate = if rule.anchored { relative.as_str() } else { name };
+                if (is_dir || !rule.dir_only) && rule.regex.is_match(candidate) {
+                    ignored = !rule.negated;
+                }
+            }
+        }
+        ignored
+    }
+}
+
+impl SearchContext {
+    /// Takes a permit for a filesystem operation, with `--io-concurrency`.
+    ///
+    /// Permits must not be held while descending into a subdirectory, since the
+    /// traversal below would wait for them.
+    fn io_permit(&self) -> Option<IoPermit<'_>> {
+        self.io_limit.as_deref().map(IoLimiter::acquire)
+    }
+
+    /// Whether a directory lies on a filesystem of one of the `--fs-type` types.
+    ///
+    /// The mount table is consulted once per device; directories that can't be
+    /// resolved are not descended into.
+    fn fs_type_matches(&mut self, dir: &Path, types: &[String]) -> bool {
+        let Some(device) = device_id(dir) else {
+            return false;
+        };
+        *self.fs_type_allowed.entry(device).or_insert_with(||
+            filesystem_type(dir).is_some_and(|fs_type| types.iter().any(|t| t.eq_ignore_ascii_case(fs_type))))
+    }
+
+    /// Whether the `--deadline` has passed, recording it for the final report.
+    fn past_deadline(&self) -> bool {
+        let past = self.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if past {
+            DEADLINE_REACHED.store(true, Ordering::SeqCst);
+        }
+        past
+    }
+
+    /// Hands a matching file to the printer, blocking while its queue is full.
+    ///
+    /// With `--content-jobs`, the match takes its place in the traversal order, behind the
+    /// files still being searched by the workers.
+    fn emit(&mut self, mut result: SearchResult) {
+        if self.file_details {
+            result.details = Some(file_details(Path::new(&result.path)));
+        }
+        // The printer only goes away once every search thread is done
+        let _ = match self.next_slot() {
+            Some(seq) => self.results.send(SearchEvent::Ordered { root: self.root, seq, results: vec![result], errors: Vec::new() }),
+            None => self.results.send(SearchEvent::Match(self.root, Box::new(result))),
+        };
+    }
+
+    /// Hands a file to the `--content-jobs` workers, blocking while too many files wait.
+    fn queue_file(&mut self, path: PathBuf, depth: Option<usize>, archives: bool) {
+        if let Some(seq) = self.next_slot() {
+            let job = ContentJob { root: self.root, seq, path, depth, archives };
+            if let Some(pool) = &self.content_pool {
+                // The workers only go away once every search thread is done
+                let _ = pool.jobs.send(job);
+            }
+        }
+    }
+
+    /// Takes the next place in the traversal order, with `--content-jobs`.
+    ///
+    /// # Returns
+    ///
+    /// The position, or `None` when results are printed as they come.
+    fn next_slot(&mut self) -> Option<usize> {
+        let pool = self.content_pool.as_ref()?;
+        pool.reorder_slots.take();
+        self.next_seq += 1;
+        Some(self.next_seq - 1)
+    }
+}
+
+/// Messages sent by the search threads to the printer.
+enum SearchEvent {
+    /// A matching file, found under the search root with the given index.
+    Match(usize, Box<SearchResult>),
+    /// With `--content-jobs`, what came out of the file or match at position `seq` in the
+    /// traversal of a root: printed once everything before it has been.
+    Ordered {
+        root: usize,
+        seq: usize,
+        results: Vec<SearchResult>,
+        errors: Vec<String>,
+    },
+    /// A search root is done, along with the errors met while searching it.
+    RootDone {
+        root: usize,
+        permission_denied_dirs: Vec<String>,
+        other_error_occurred: bool,
+        error_message: String,
+    },
+}
+
+/// Key used by `--sort` to order the results.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SortKey {
+    /// File name, then full path.
+    Name,
+    /// Full path.
+    Path,
+    /// Size in bytes, smallest first.
+    Size,
+    /// Modification time, oldest first.
+    Mtime,
+    /// Fuzzy match score, best first; only with `--fuzzy`.
+    Score,
+    /// Number of matching lines, most first; only with `--count-lines` or `--min-matches`.
+    Matches,
+}
+
+/// A directory listing stored in the cache, valid while the directory's mtime is unchanged.
+#[derive(Serialize, Deserialize)]
+struct CachedDir {
+    modified_secs: u64,
+    modified_nanos: u32,
+    entries: Vec<String>,
+}
+
+/// On-disk cache of directory listings for one search root, used by `--cache`.
+///
+/// Listings are stored as JSON under the OS cache directory. A directory's mtime changes
+/// whenever entries are added, removed or renamed, so a cached listing is reused only when
+/// the mtime still matches. Files are always re-checked against the search criteria.
+#[derive(Default)]
+struct DirCache {
+    file: PathBuf,
+    /// Listings loaded from disk, taken out as directories are visited.
+    previous: HashMap<String, CachedDir>,
+    /// Listings of the directories visited in this run, written back by `save`.
+    current: HashMap<String, CachedDir>,
+}
+
+impl DirCache {
+    /// Returns the directory holding the cache files, e.g. `~/.cache/find_file` on Linux.
+    fn directory() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("find_file"))
+    }
+
+    /// Loads the cache for a search root, starting empty when none exists or it can't be read.
+    ///
+    /// # Parameters
+    ///
+    /// * `root` - A reference to the `Path` of the search root.
+    ///
+    /// # Returns
+    ///
+    /// The `DirCache` for `root`, or `None` if the OS has no cache directory.
+    fn load(root: &Path) -> Option<DirCache> {
+        let mut hasher = DefaultHasher::new();
+        fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf()).hash(&mut hasher);
+        let file = DirCache::directory()?.join(format!("{:016x}.json", hasher.finish()));
+
+        let previous = fs::read_to_string(&file).ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Some(DirCache { file, previous, current: HashMap::new() })
+    }
+
+    /// Returns the cached entry names of `dir` if its mtime is unchanged.
+    fn lookup(&mut self, dir: &Path, modified: SystemTime) -> Option<Vec<String>> {
+        let key = dir.to_str()?.to_string();
+        let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+        let cached = self.previous.remove(&key)?;
+        if cached.modified_secs != since_epoch.as_secs() || cached.modified_nanos != since_epoch.subsec_nanos() {
+            return None;
+        }
+        let entries = cached.entries.clone();
+        self.current.insert(key, cached);
+        Some(entries)
+    }
+
+    /// Records the listing of `dir`; listings with unreadable or non-UTF-8 entries are not cached.
+    fn store(&mut self, dir: &Path, modified: SystemTime, entries: &[io::Result<PathBuf>]) {
+        let (Some(key), Ok(since_epoch)) = (dir.to_str(), modified.duration_since(UNIX_EPOCH)) else {
+            return;
+        };
+        let names: Option<Vec<String>> = entries.iter()
+            .map(|entry| entry.as_ref().ok()?.file_name()?.to_str().map(String::from))
+            .collect();
+        if let Some(entries) = names {
+            self.current.insert(key.to_string(), CachedDir {
+                modified_secs: since_epoch.as_secs(),
+                modified_nanos: since_epoch.subsec_nanos(),
+                entries,
+            });
+        }
+    }
+
+    /// Writes the listings visited in this run back to disk.
+    fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let content = serde_json::to_string(&self.current).map_err(io::Error::other)?;
+        fs::write(&self.file, content)
+    }
+
+    /// Deletes every cached listing.
+    fn clear() -> io::Result<()> {
+        match DirCache::directory() {
+            Some(dir) if dir.exists() => fs::remove_dir_all(dir),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// When a search with the same arguments last completed, stored for `--since-last-run`.
+#[derive(Serialize, Deserialize)]
+struct LastRunState {
+    started_secs: u64,
+    started_nanos: u32,
+}
+
+/// The `--since-last-run` state file of the current search.
+///
+/// Runs are keyed by the working directory and the command line arguments (without
+/// `--since-last-run` and `--reset-last-run`), so that each distinct search keeps its own
+/// timestamp under the OS state directory.
+struct LastRun {
+    file: PathBuf,
+}
+
+impl LastRun {
+    /// Returns the state file of the search described by the command line, or `None`
+    /// if the OS has neither a state nor a local data directory.
+    fn for_current_args() -> Option<LastRun> {
+        let mut hasher = DefaultHasher::new();
+        std::env::current_dir().ok().and_then(|dir| fs::canonicalize(dir).ok()).hash(&mut hasher);
+        for arg in std::env::args_os().skip(1).filter(|arg| arg != "--since-last-run" && arg != "--reset-last-run") {
+            arg.hash(&mut hasher);
+        }
+        let dir = dirs::state_dir().or_else(dirs::data_local_dir)?.join("find_file").join("last_run");
+        Some(LastRun { file: dir.join(format!("{:016x}.json", hasher.finish())) })
+    }
+
+    /// Reads the start time of the last successful run, if any.
+    fn previous(&self) -> Option<SystemTime> {
+        let content = fs::read_to_string(&self.file).ok()?;
+        let state: LastRunState = serde_json::from_str(&content).ok()?;
+        Some(UNIX_EPOCH + Duration::new(state.started_secs, state.started_nanos))
+    }
+
+    /// Records the start time of this run, so that files changed while it ran are seen next time.
+    fn save(&self, started: SystemTime) -> io::Result<()> {
+        let since_epoch = started.duration_since(UNIX_EPOCH).map_err(io::Error::other)?;
+        if let Some(parent) = self.file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let state = LastRunState { started_secs: since_epoch.as_secs(), started_nanos: since_epoch.subsec_nanos() };
+        fs::write(&self.file, serde_json::to_string(&state).map_err(io::Error::other)?)
+    }
+
+    /// Deletes the stored time, if there is one.
+    fn clear(&self) -> io::Result<()> {
+        match fs::remove_file(&self.file) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Counters gathered during a search and printed with `--stats`.
+#[derive(Default, Debug)]
+struct SearchStats {
+    duplicates_collapsed: usize,
+    /// Number of matches `--sample` drew its sample from.
+    sampled_from: Option<usize>,
+    /// The names `--dedupe-by-name` found more than once, with the first path and the later ones.
+    duplicate_names: Vec<(String, String, Vec<String>)>,
+    elapsed: Duration,
+    /// Time spent in the `--warm` pass, not included in `elapsed`.
+    warm_elapsed: Option<Duration>,
+    /// Empty files that were not opened for a content search.
+    zero_byte_skipped: usize,
+    /// Directories whose entries were listed.
+    dirs_searched: usize,
+    /// Files checked against the search criteria.
+    files_checked: usize,
+}
+
+/// Format used to report matching files.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Human readable summary printed once the search is done.
+    Text,
+    /// A single JSON document (`SearchReport`) printed once the search is done.
+    Json,
+    /// One JSON object per line, printed as soon as a file matches.
+    Jsonl,
+    /// Like `Jsonl`, with the type, permissions, owner and matching line numbers of each file.
+    JsonlFull,
+    /// A self-contained HTML page printed once the search is done.
+    Html,
+}
+
+/// Version of the `--format json` schema; bump it on any breaking change to `SearchReport`
+/// or `JsonRecord`, and update the `--format` help text accordingly.
+const JSON_SCHEMA_VERSION: u32 = 1;
+
+/// File format of `--export-matches`, chosen by the extension of the file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExportFormat {
+    /// A JSON array of `{"path", "line", "text"}` objects.
+    Json,
+    /// A `path,line,text` header, then one row per line, quoted as in RFC 4180.
+    Csv,
+    /// One `path:line:text` line per matching line, like `grep -n`.
+    Text,
+}
+
+impl ExportFormat {
+    /// Picks the format from the extension of `path`, ignoring its case.
+    ///
+    /// # Returns
+    ///
+    /// The format, or `None` if the extension is not `.json`, `.csv` or `.txt`.
+    fn for_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "json" => Some(ExportFormat::Json),
+            "csv" => Some(ExportFormat::Csv),
+            "txt" => Some(ExportFormat::Text),
+            _ => None,
+        }
+    }
+}
+
+/// A matching line, as written by `--export-matches`.
+#[derive(Serialize)]
+struct ExportedLine<'a> {
+    path: &'a str,
+    line: usize,
+    text: &'a str,
+}
+
+/// Writes every matching line of the results, for `--export-matches`.
+///
+/// # Parameters
+///
+/// * `out` - Where the export is written.
+/// * `format` - The `ExportFormat` to write it in.
+/// * `files` - The matches, with their `line_numbers` and `matched_lines`.
+///
+/// # Returns
+///
+/// An `io::Result` telling whether the export could be written.
+fn write_match_export(out: &mut dyn Write, format: ExportFormat, files: &[SearchResult]) -> io::Result<()> {
+    let lines = files.iter().flat_map(|file| file.line_numbers.iter().zip(&file.matched_lines)
+        .map(|(&line, text)| ExportedLine { path: &file.path, line, text }));
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, &lines.collect::<Vec<_>>()).map_err(io::Error::other)?;
+            writeln!(out)
+        }
+        ExportFormat::Csv => {
+            writeln!(out, "path,line,text")?;
+            for line in lines {
+                writeln!(out, "{},{},{}", csv_field(line.path), line.line, csv_field(line.text))?;
+            }
+            Ok(())
+        }
+        ExportFormat::Text => {
+            for line in lines {
+                writeln!(out, "{}:{}:{}", line.path, line.line, line.text)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Quotes a CSV field when it holds a comma, a quote or a line break, doubling its quotes.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// A `--format jsonl-full` record: the `--format jsonl` one, with the `FileDetails`.
+#[derive(Serialize)]
+struct FullJsonRecord<'a> {
+    #[serde(flatten)]
+    record: JsonRecord<'a>,
+    #[serde(flatten)]
+    details: &'a FileDetails,
+    #[serde(skip_serializing_if = "<[usize]>::is_empty")]
+    line_numbers: &'a [usize],
+}
+
+/// The document printed by `--show-config-json`.
+#[derive(Serialize)]
+struct EffectiveConfig<'a> {
+    /// The search roots, after `~` expansion and the default to the current directory.
+    directories: &'a [PathBuf],
+    #[serde(flatten)]
+    options: &'a Args,
+}
+
+/// Serializes a `clap::ValueEnum` option as it is spelled on the command line.
+fn serialize_value_enum<T: clap::ValueEnum, S: serde::Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value.to_possible_value().as_ref().map_or("", |v| v.get_name()))
+}
+
+/// Serializes an optional `clap::ValueEnum` option, `null` when it isn't given.
+fn serialize_optional_value_enum<T: clap::ValueEnum, S: serde::Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serialize_value_enum(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serializes the values of a repeatable `clap::ValueEnum` option as they are spelled on
+/// the command line.
+fn serialize_value_enums<T: clap::ValueEnum, S: serde::Serializer>(values: &[T], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().filter_map(|value| value.to_possible_value().map(|v| v.get_name().to_string())))
+}
+
+/// Serializes an optional value with its `Debug` form, such as `1.5s` for a `Duration`.
+fn serialize_as_debug<T: std::fmt::Debug, S: serde::Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.collect_str(&format_args!("{:?}", value)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// The document printed by `--format json`.
+#[derive(Serialize)]
+struct SearchReport<'a> {
+    #[serde(rename = "version")]
+    schema_version: u32,
+    #[serde(rename = "results")]
+    files: Vec<JsonRecord<'a>>,
+    #[serde(rename = "permission_denied")]
+    permission_denied_dirs: Vec<&'a str>,
+    /// With `--max-error-entries`, the number of directories before the list was cut.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    permission_denied_count: Option<usize>,
+    /// With `--max-error-entries`, whether `permission_denied` leaves directories out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    permission_denied_truncated: Option<bool>,
+    /// With `--total-matches`, the number of matching lines across all results.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_matches: Option<usize>,
+    /// With `--total-matches`, whether `--max-matches-per-file` made the total a lower bound.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_matches_truncated: Option<bool>,
+    #[serde(rename = "errors")]
+    error_messages: Vec<&'a str>,
+}
+
+/// A JSON record describing a matching file, used by both JSON formats.
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matching_lines: Option<usize>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    matches: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blame: Option<&'a Blame>,
+}
+
+impl Args {
+    /// Whether dotfiles can be reported, from `--hidden-files` or `--all`.
+    fn include_hidden_files(&self) -> bool {
+        self.all || self.hidden_files
+    }
+
+    /// Whether dot-directories are descended into, from `--hidden-dirs` or `--all`.
+    fn include_hidden_dirs(&self) -> bool {
+        self.all || self.hidden_dirs
+    }
+}
+
+/// Kind of entry selected with `--type`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum EntryType {
+    /// Regular files, and symlinks to them.
+    #[value(name = "f")]
+    File,
+    /// Symbolic links themselves.
+    #[value(name = "l")]
+    Symlink,
+    /// Unix domain sockets.
+    #[value(name = "s")]
+    Socket,
+    /// Named pipes.
+    #[value(name = "p")]
+    Fifo,
+    /// Block devices.
+    #[value(name = "b")]
+    BlockDevice,
+    /// Character devices.
+    #[value(name = "c")]
+    CharDevice,
+}
+
+impl EntryType {
+    /// Special files are never read, and only matched when asked for with `--type`.
+    fn is_special(self) -> bool {
+        !matches!(self, EntryType::File | EntryType::Symlink)
+    }
+
+    /// Maps a file type to an `EntryType`, or `None` for directories and unknown types.
+    fn of(file_type: fs::FileType) -> Option<EntryType> {
+        #[cfg(unix)]
+        use std::os::unix::fs::FileTypeExt;
+
+        if file_type.is_symlink() {
+            return Some(EntryType::Symlink);
+        }
+        if file_type.is_file() {
+            return Some(EntryType::File);
+        }
+        #[cfg(unix)]
+        {
+            if file_type.is_socket() {
+                return Some(EntryType::Socket);
+            }
+            if file_type.is_fifo() {
+                return Some(EntryType::Fifo);
+            }
+            if file_type.is_block_device() {
+                return Some(EntryType::BlockDevice);
+            }
+            if file_type.is_char_device() {
+                return Some(EntryType::CharDevice);
+            }
+        }
+        None
+    }
+}
+
+/// Windows file attribute selected with `--attr`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum FileAttribute {
+    /// Hidden from directory listings by default.
+    Hidden,
+    /// Can't be written to or deleted.
+    Readonly,
+    /// Used by the operating system.
+    System,
+    /// Marked for backup, set whenever the file changes.
+    Archive,
+}
+
+impl FileAttribute {
+    /// The `FILE_ATTRIBUTE_*` bit of the attribute.
+    #[cfg(windows)]
+    fn bit(self) -> u32 {
+        match self {
+            FileAttribute::Readonly => 0x1,
+            FileAttribute::Hidden => 0x2,
+            FileAttribute::System => 0x4,
+            FileAttribute::Archive => 0x20,
+        }
+    }
+}
+
+/// Kind of entry an exclude pattern is applied to.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ExcludeType {
+    /// Regular files: matching files are left out of the results.
+    #[value(name = "f")]
+    File,
+    /// Directories: matching directories are not descended into.
+    #[value(name = "d")]
+    Dir,
+}
+
+/// A programming language `--lang` can find, with how its files are recognized.
+struct Language {
+    /// Name given to `--lang`.
+    name: &'static str,
+    /// File extensions, compared case-insensitively.
+    extensions: &'static [&'static str],
+    /// Interpreters named by the `#!` line of its scripts, without their version suffix.
+    interpreters: &'static [&'static str],
+}
+
+/// The languages known to `--lang`.
+const LANGUAGES: &[Language] = &[
+    Language { name: "c", extensions: &["c", "h"], interpreters: &[] },
+    Language { name: "cpp", extensions: &["cpp", "cc", "cxx", "hpp", "hh", "hxx"], interpreters: &[] },
+    Language { name: "csharp", extensions: &["cs"], interpreters: &[] },
+    Language { name: "go", extensions: &["go"], interpreters: &[] },
+    Language { name: "java", extensions: &["java"], interpreters: &[] },
+    Language { name: "javascript", extensions: &["js", "mjs", "cjs", "jsx"], interpreters: &["node", "nodejs", "deno", "bun"] },
+    Language { name: "typescript", extensions: &["ts", "mts", "cts", "tsx"], interpreters: &["ts-node", "tsx"] },
+    Language { name: "kotlin", extensions: &["kt", "kts"], interpreters: &[] },
+    Language { name: "lua", extensions: &["lua"], interpreters: &["lua", "luajit"] },
+    Language { name: "perl", extensions: &["pl", "pm"], interpreters: &["perl"] },
+    Language { name: "php", extensions: &["php"], interpreters: &["php"] },
+    Language { name: "python", extensions: &["py", "pyw", "pyi"], interpreters: &["python", "pypy"] },
+    Language { name: "ruby", extensions: &["rb"], interpreters: &["ruby"] },
+    Language { name: "rust", extensions: &["rs"], interpreters: &[] },
+    Language { name: "shell", extensions: &["sh", "bash", "zsh", "ksh"], interpreters: &["sh", "bash", "dash", "zsh", "ksh", "ash"] },
+    Language { name: "swift", extensions: &["swift"], interpreters: &["swift"] },
+];
+
+/// Regular expressions compiled once from the command line arguments.
+struct Patterns {
+    /// Name (and, with `--content`, content) patterns from `--filter`.
+    filter: Vec<Regex>,
+    /// The `--filter`, `--name-regex` and `--name-glob` patterns as matched against file
+    /// names, accent-folded with `--ascii-fold`.
+    name: Vec<Regex>,
+    /// The `--name` patterns, one of which every reported file name must match.
+    required_name: Vec<Regex>,
+    /// The `--filter` patterns lowercased for `--fuzzy`, accent-folded with `--ascii-fold`.
+    fuzzy: Vec<String>,
+    /// Content patterns that must not appear, from `--content-not`.
+    content_not: Vec<Regex>,
+    /// Regexes of the lines left out of the content search, from `--content-exclude`.
+    content_exclude: Vec<Regex>,
+    /// The `--exclude` pattern, one regex per brace alternative.
+    exclude: Vec<Regex>,
+    /// The `--path-contains` text, lowercased with `--ignore-case`.
+    path_contains: Option<String>,
+    /// The regex and replacement text given to `--replace`.
+    replace: Option<(Regex, String)>,
+    /// The parsed `--format-template`.
+    template: Option<Vec<TemplatePart>>,
+    /// The `--shebang` regex.
+    shebang: Option<Regex>,
+    /// The `--lang` languages.
+    languages: Vec<&'static Language>,
+    /// The canonical `--links-to` path.
+    links_to: Option<PathBuf>,
+    /// The `--contains-file` glob, matched against whole file names.
+    contains_file: Option<Regex>,
+    /// The `--force-include` patterns, with the syntax of ignore file rules.
+    force_include: Vec<IgnoreRule>,
+    /// Start of the last run, with `--since-last-run`; only files modified later match.
+    modified_after: Option<SystemTime>,
+    /// Smallest and largest depth of the reported entries, from `--filter-by-depth-range`.
+    depth_range: Option<(usize, Option<usize>)>,
+    /// The `--filter` patterns without their outer `*`, for `--only-matching` and the
+    /// highlighting of the HTML report.
+    spans: Vec<Regex>,
+}
+
+/// A piece of a `--format-template`.
+#[derive(Debug, PartialEq, Eq)]
+enum TemplatePart {
+    /// Text printed as is, with `{{` and `}}` already unescaped.
+    Literal(String),
+    /// `{path}`: the path of the match.
+    Path,
+    /// `{name}`: the file name of the match.
+    Name,
+    /// `{N}`: capture group `N` of the matching content line, `{0}` being the whole line.
+    Capture(usize),
+}
+
+impl Patterns {
+    /// Compiles the patterns given in `args`.
+    ///
+    /// Invalid filter and exclude patterns are skipped, while an invalid `--replace`
+    /// pattern is an error since files would be rewritten, and so is an invalid template.
+    ///
+    /// # Parameters
+    ///
+    /// * `args` - A reference to `Args` containing the patterns.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the compiled `Patterns`, or a message describing the invalid pattern.
+    fn from_args(args: &Args) -> Result<Patterns, String> {
+        let replace = match args.replace.as_slice() {
+            [pattern, replacement] => {
+                let re = Regex::new(pattern)
+                    .map_err(|e| format!("invalid --replace pattern '{}': {}", pattern, e))?;
+                Some((re, replacement.clone()))
+            }
+            _ => None,
+        };
+        let template = args.format_template.as_deref()
+            .map(|template| parse_template(template).map_err(|e| format!("invalid --format-template '{}': {}", template, e)))
+            .transpose()?;
+
+        let shebang = args.shebang.as_deref()
+            .map(|pattern| RegexBuilder::new(pattern).case_insensitive(args.ignore_case).build()
+                .map_err(|e| format!("invalid --shebang pattern '{}': {}", pattern, e)))
+            .transpose()?;
+
+        let content_exclude = args.content_exclude.iter()
+            .map(|pattern| RegexBuilder::new(pattern).case_insensitive(args.ignore_case).build()
+                .map_err(|e| format!("invalid --content-exclude pattern '{}': {}", pattern, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let links_to = args.links_to.as_deref()
+            .map(|target| fs::canonicalize(target).map_err(|e| format!("invalid --links-to '{}': {}", target.display(), e)))
+            .transpose()?;
+
+        let expand = |patterns: &[String]| -> Vec<String> { patterns.iter().flat_map(|pattern| expand_braces(pattern)).collect() };
+
+        // The alternatives of a single glob make up a single regex
+        let contains_file = args.contains_file.as_deref()
+            .map(|pattern| {
+                let alternatives: Vec<String> = expand_braces(pattern).iter().map(|glob| name_glob_regex(glob)).collect();
+                RegexBuilder::new(&alternatives.join("|")).case_insensitive(args.ignore_case).build()
+                    .map_err(|e| format!("invalid --contains-file pattern '{}': {}", pattern, e))
+            })
+            .transpose()?;
+
+        let force_include = expand(&args.force_include).iter()
+            .map(|pattern| force_include_rule(pattern).ok_or_else(|| format!("invalid --force-include pattern '{}'", pattern)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let languages = args.lang.iter()
+            .map(|name| LANGUAGES.iter().find(|language| language.name.eq_ignore_ascii_case(name.trim()))
+                .ok_or_else(|| format!("unknown --lang '{}': expected one of {}", name,
+                    LANGUAGES.iter().map(|language| language.name).collect::<Vec<_>>().join(", "))))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let glob = |pattern: &str| glob_regex(pattern, args.ignore_case);
+        let fold = |pattern: &String| if args.ascii_fold { ascii_fold(pattern) } else { pattern.clone() };
+        let filter = expand(&args.filter);
+
+        // Unlike --filter, the explicit name patterns are rejected when invalid. With --name and
+        // --content, --filter is left to the content
+        let mut name: Vec<Regex> = if args.content && !args.name.is_empty() {
+            Vec::new()
+        } else {
+            filter.iter().filter_map(|pattern| glob(&fold(pattern))).collect()
+        };
+        for pattern in &args.name_regex {
+            let re = RegexBuilder::new(&fold(pattern)).case_insensitive(args.ignore_case).build()
+                .map_err(|e| format!("invalid --name-regex pattern '{}': {}", pattern, e))?;
+            name.push(re);
+        }
+        let required_name = expand(&args.name).iter()
+            .map(|pattern| glob(&fold(pattern)).ok_or_else(|| format!("invalid --name pattern '{}'", pattern)))
+            .collect::<Result<Vec<_>, _>>()?;
+        for pattern in &expand(&args.name_glob) {
+            let re = RegexBuilder::new(&name_glob_regex(&fold(pattern))).case_insensitive(args.ignore_case).build()
+                .map_err(|e| format!("invalid --name-glob pattern '{}': {}", pattern, e))?;
+            name.push(re);
+        }
+
+        Ok(Patterns {
+            filter: filter.iter().filter_map(|pattern| glob(pattern)).collect(),
+            name,
+            required_name,
+            fuzzy: filter.iter().map(|pattern| fold(pattern).to_lowercase()).collect(),
+            content_not: expand(&args.content_not).iter().filter_map(|pattern| glob(pattern)).collect(),
+            content_exclude,
+            exclude: args.exclude.as_deref().map_or_else(Vec::new, |pattern| expand_braces(pattern).iter().filter_map(|pattern| glob(pattern)).collect()),
+            path_contains: args.path_contains.as_ref()
+                .map(|text| if args.ignore_case { text.to_lowercase() } else { text.clone() }),
+            replace,
+            template,
+            shebang,
+            languages,
+            links_to,
+            contains_file,
+            force_include,
+            modified_after: None,
+            depth_range: None,
+            spans: if args.only_matching || args.format == OutputFormat::Html {
+                filter.iter().filter_map(|pattern| span_regex(pattern, args.ignore_case)).collect()
+            } else {
+                Vec::new()
+            },
+        })
+    }
+}
+
+/// Expands the brace alternatives of a pattern, like a shell: `*.{rs,toml}` becomes
+/// `*.rs` and `*.toml`.
+///
+/// Alternatives can be nested (`{a,b{1,2}}`) and a pattern can hold several groups, which
+/// expand to every combination. Braces without a comma are kept, and so are groups made only
+/// of digits, such as `{2,4}`, which are regex repetitions. A `\` escapes the next character.
+///
+/// # Parameters
+///
+/// * `pattern` - The pattern as given on the command line.
+///
+/// # Returns
+///
+/// The expanded patterns, in order; just `pattern` when there is nothing to expand.
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(expand_braces("*.{rs,toml}"), vec!["*.rs", "*.toml"]);
+/// assert_eq!(expand_braces("{a,b{1,2}}x"), vec!["ax", "b1x", "b2x"]);
+/// ```
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let bytes = pattern.as_bytes();
+    let mut open = 0;
+    while open < bytes.len() {
+        match bytes[open] {
+            b'\\' => {
+                open += 2;
+                continue;
+            }
+            b'{' => {}
+            _ => {
+                open += 1;
+                continue;
+            }
+        }
+
+        // Find the matching brace and the commas at this level
+        let mut depth = 0;
+        let mut commas = Vec::new();
+        let mut close = None;
+        let mut i = open + 1;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\\' => i += 1,
+                b'{' => depth += 1,
+                b'}' if depth == 0 => {
+                    close = Some(i);
+                    break;
+                }
+                b'}' => depth -= 1,
+                b',' if depth == 0 => commas.push(i),
+                _ => {}
+            }
+            i += 1;
+        }
+        let Some(close) = close else {
+            break;
+        };
+
+        let bounds: Vec<usize> = std::iter::once(open).chain(commas.iter().copied()).chain(std::iter::once(close)).collect();
+        let alternatives: Vec<&str> = bounds.windows(2).map(|w| &pattern[w[0] + 1..w[1]]).collect();
+        let repetition = alternatives.iter().all(|alt| alt.bytes().all(|b| b.is_ascii_digit()));
+        if commas.is_empty() || repetition {
+            open += 1;
+            continue;
+        }
+
+        let (prefix, suffix) = (&pattern[..open], &pattern[close + 1..]);
+        return alternatives.iter()
+            .flat_map(|alt| expand_braces(&format!("{}{}{}", prefix, alt, suffix)))
+            .collect();
+    }
+    vec![pattern.to_string()]
+}
+
+/// Removes accents from `text` by decomposing it and dropping combining marks (é becomes e).
+///
+/// This is a best-effort transliteration: letters without a decomposition, such as ø or ß,
+/// are kept unchanged.
+///
+/// # Parameters
+///
+/// * `text` - The text to fold.
+///
+/// # Returns
+///
+/// The folded text, with case preserved.
+fn ascii_fold(text: &str) -> String {
+    text.nfd().filter(|c| !is_combining_mark(*c)).collect()
+}
+
+/// Scores how well a file name matches a pattern in `--fuzzy` mode.
+///
+/// Every character of the pattern must appear in the name, in order and ignoring case.
+/// Each matched character is worth a point, with bonuses for characters that follow the
+/// previous match directly or that start a word (after `_`, `-`, `.`, a space, or at the
+/// start of the name).
+///
+/// # Parameters
+///
+/// * `pattern` - The lowercased pattern.
+/// * `name` - The file name to score.
+///
+/// # Returns
+///
+/// The score, higher being better, or `None` if the pattern doesn't match.
+///
+/// # Example
+///
+/// ```
+/// assert!(fuzzy_score("mr", "main.rs") > fuzzy_score("mr", "summary.txt"));
+/// assert_eq!(fuzzy_score("xyz", "main.rs"), None);
+/// ```
+fn fuzzy_score(pattern: &str, name: &str) -> Option<u32> {
+    let mut wanted = pattern.chars().filter(|c| *c != '*').peekable();
+    let mut score = 0;
+    let mut previous: Option<char> = None;
+    let mut previous_matched = false;
+
+    for c in name.chars().flat_map(char::to_lowercase) {
+        let Some(&next) = wanted.peek() else { break };
+        let matched = c == next;
+        if matched {
+            wanted.next();
+            score += 1;
+            if previous_matched {
+                score += 2;
+            }
+            if previous.is_none_or(|p| matches!(p, '_' | '-' | '.' | ' ')) {
+                score += 3;
+            }
+        }
+        previous_matched = matched;
+        previous = Some(c);
+    }
+
+    wanted.peek().is_none().then_some(score)
+}
+
+/// Splits a `--format-template` into literal text and placeholders.
+///
+/// # Parameters
+///
+/// * `template` - The template as given on the command line.
+///
+/// # Returns
+///
+/// The parts of the template, or a message describing the first invalid placeholder.
+///
+/// # Example
+///
+/// ```
+/// let parts = parse_template("{path}: {1}").unwrap();
+/// assert_eq!(parts, vec![TemplatePart::Path, TemplatePart::Literal(": ".into()), TemplatePart::Capture(1)]);
+/// ```
+fn parse_template(template: &str) -> Result<Vec<TemplatePart>, String> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest.find('}').ok_or("unclosed '{' (write '{{' for a literal brace)")?;
+                let part = match &rest[..end] {
+                    "path" => TemplatePart::Path,
+                    "name" => TemplatePart::Name,
+                    group => TemplatePart::Capture(group.parse()
+                        .map_err(|_| format!("unknown placeholder {{{}}}", group))?),
+                };
+                if !literal.is_empty() {
+                    parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(part);
+                chars = rest[end + 1..].chars();
+            }
+            '}' => return Err("unmatched '}' (write '}}' for a literal brace)".into()),
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(TemplatePart::Literal(literal));
+    }
+
+    Ok(parts)
+}
+
+/// Translates a `--name-glob` pattern into an anchored regex.
+///
+/// `*` matches any run of characters, `?` a single character, and `[...]` a character
+/// class, negated with `[!...]`. A `\` makes the next character literal, and every other
+/// character is literal too, as is an unterminated `[`.
+///
+/// # Parameters
+///
+/// * `pattern` - The glob as given on the command line.
+///
+/// # Returns
+///
+/// The regex source, matching whole file names.
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(name_glob_regex("a?[!0-9].rs"), r"^a.[^0-9]\.rs$");
+/// ```
+fn name_glob_regex(pattern: &str) -> String {
+    let mut regex = String::from("^");
+    let mut rest = pattern;
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        match c {
+            '\\' => {
+                let escaped = rest.chars().next().unwrap_or('\\');
+                rest = &rest[escaped.len_utf8().min(rest.len())..];
+                regex.push_str(&regex::escape(&escaped.to_string()));
+            }
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                let negated = rest.starts_with('!');
+                let body_start = usize::from(negated);
+                // A `]` right after `[` or `[!` belongs to the class
+                let close = rest[body_start..].chars().next()
+                    .map(|first| body_start + first.len_utf8())
+                    .and_then(|from| rest[from..].find(']').map(|i| from + i));
+                match close {
+                    Some(end) => {
+                        regex.push('[');
+                        if negated {
+                            regex.push('^');
+                        }
+                        // Keep ranges, but not the regex class syntax (nesting, `&&`, `~~`)
+                        for c in rest[body_start..end].chars() {
+                            if matches!(c, '\\' | '[' | ']' | '&' | '~') {
+                                regex.push('\\');
+                            }
+                            regex.push(c);
+                        }
+                        regex.push(']');
+                        rest = &rest[end + 1..];
+                    }
+                    None => regex.push_str(r"\["),
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Compiles a `--force-include` pattern into an ignore rule that matches its entries.
+///
+/// A pattern with a `/` matches the end of the path of an entry, as found during the search,
+/// unless it is absolute; a trailing `/` only matches directories, and a leading `./` is dropped.
+///
+/// # Parameters
+///
+/// * `pattern` - The pattern as given on the command line.
+///
+/// # Returns
+///
+/// The rule, or `None` if the pattern is empty.
+///
+/// # Example
+///
+/// ```
+/// assert!(force_include_rule("vendor/keep").unwrap().regex.is_match("./third_party/vendor/keep"));
+/// ```
+fn force_include_rule(pattern: &str) -> Option<IgnoreRule> {
+    let pattern = pattern.strip_prefix("./").unwrap_or(pattern);
+    let (dir_only, pattern) = match pattern.strip_suffix('/') {
+        Some(rest) => (true, rest),
+        None => (false, pattern),
+    };
+    let anchored = pattern.contains('/');
+    let regex = if anchored && !pattern.starts_with('/') {
+        ignore_glob_regex(&format!("**/{}", pattern))?
+    } else {
+        ignore_glob_regex(pattern)?
+    };
+    Some(IgnoreRule { regex, negated: false, dir_only, anchored })
+}
+
+impl Patterns {
+    /// Checks whether an entry matches a `--force-include` pattern, which no exclusion overrides.
+    ///
+    /// # Parameters
+    ///
+    /// * `path` - A reference to the `Path` of the entry, as found during the search.
+    /// * `is_dir` - Whether the entry is a directory.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the entry has to be kept, `false` otherwise.
+    fn force_included(&self, path: &Path, is_dir: bool) -> bool {
+        if self.force_include.is_empty() {
+            return false;
+        }
+        let full_path = path.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+        let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+        self.force_include.iter().any(|rule| (is_dir || !rule.dir_only) &&
+            rule.regex.is_match(if rule.anchored { &full_path } else { &name }))
+    }
+}
+
+/// Compiles a `.findfileignore` pattern into an anchored regex.
+///
+/// As in `.gitignore`, a `\` makes the next character literal.
+///
+/// # Parameters
+///
+/// * `pattern` - The pattern, without its `!` and trailing `/` markers.
+///
+/// # Returns
+///
+/// The compiled `Regex`, or `None` if the pattern is empty.
+fn ignore_glob_regex(pattern: &str) -> Option<Regex> {
+    if pattern.is_empty() {
+        return None;
+    }
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no directory at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '\\' => regex.push_str(&regex::escape(&chars.next().unwrap_or('\\').to_string())),
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).ok()
+}
+
+/// Compiles a pattern where `*` matches any run of characters into an anchored regex.
+///
+/// # Parameters
+///
+/// * `pattern` - The pattern as given on the command line.
+/// * `ignore_case` - If `true`, the regex matches regardless of case.
+///
+/// # Returns
+///
+/// The compiled `Regex`, or `None` if the resulting expression is invalid.
+fn glob_regex(pattern: &str, ignore_case: bool) -> Option<Regex> {
+    RegexBuilder::new(&format!("^{}$", pattern.replace("*", ".*")))
+        .case_insensitive(ignore_case)
+        .build()
+        .ok()
+}
+
+/// Compiles a `--filter` pattern into a regex matching only the part of a line it is about.
+///
+/// Leading and trailing `*` are dropped, since they would stretch every match to the whole
+/// line; whichever end has none stays anchored, as with `glob_regex`.
+///
+/// # Parameters
+///
+/// * `pattern` - The pattern as given on the command line.
+/// * `ignore_case` - If `true`, the regex matches regardless of case.
+///
+/// # Returns
+///
+/// The compiled `Regex`, or `None` if the resulting expression is invalid.
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(span_regex(r"*https?://\S+*", false).unwrap().as_str(), r"https?://\S+");
+/// ```
+fn span_regex(pattern: &str, ignore_case: bool) -> Option<Regex> {
+    let core = pattern.trim_start_matches('*');
+    let start = if core.len() == pattern.len() { "^" } else { "" };
+    let trimmed = core.trim_end_matches('*');
+    let end = if trimmed.len() == core.len() { "$" } else { "" };
+    RegexBuilder::new(&format!("{}{}{}", start, trimmed.replace("*", ".*"), end))
+        .case_insensitive(ignore_case)
+        .build()
+        .ok()
+}
+
+fn main() {
+    let mut args = Args::parse();
+    // From here on, --pattern is just more --filter patterns
+    args.filter.append(&mut args.pattern);
+
+    #[cfg(not(unix))]
+    if let Some(special) = args.file_type.iter().find(|t| t.is_special()) {
+        eprintln!("{}", format!("Error: --type {:?} is only supported on Unix", special).red().bold());
+        std::process::exit(1);
+    }
+
+    #[cfg(not(unix))]
+    if args.audit_perms {
+        eprintln!("{}", "Error: --audit-perms is only supported on Unix".red().bold());
+        std::process::exit(1);
+    }
+
+    #[cfg(not(unix))]
+    if args.mine {
+        eprintln!("{}", "Error: --mine is only supported on Unix".red().bold());
+        std::process::exit(1);
+    }
+
+    #[cfg(not(unix))]
+    if args.exec_dir.is_some() {
+        eprintln!("{}", "Error: --exec-dir is only supported on Unix".red().bold());
+        std::process::exit(1);
+    }
+
+    #[cfg(not(unix))]
+    if args.xattr.is_some() {
+        eprintln!("{}", "Warning: --xattr is only supported on Unix and is ignored".yellow());
+    }
+
+    #[cfg(not(windows))]
+    if !args.attr.is_empty() {
+        eprintln!("{}", "Warning: --attr is only supported on Windows and is ignored".yellow());
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if !args.fs_type.is_empty() {
+        eprintln!("{}", "Error: --fs-type is only supported on Linux".red().bold());
+        std::process::exit(1);
+    }
+
+    #[cfg(not(unix))]
+    if args.same_filesystem {
+        eprintln!("{}", "Warning: --same-filesystem is only supported on Unix and is ignored".yellow());
+    }
+
+    // The files found while watching are printed one by one, which only text and JSON Lines allow
+    if args.watch && matches!(args.format, OutputFormat::Json | OutputFormat::Html) {
+        eprintln!("{}", "Error: --format json and --format html write a single document and can't be combined with --watch; use --format jsonl".red().bold());
+        std::process::exit(1);
+    }
+
+    // A document cut at an arbitrary byte can no longer be parsed
+    if args.max_output_bytes.is_some() && args.format != OutputFormat::Text {
+        eprintln!("{}", "Error: --max-output-bytes only applies to --format text, since it would cut the other formats mid-record".red().bold());
+        std::process::exit(1);
+    }
+
+    if args.sort == Some(SortKey::Score) && !args.fuzzy {
+        eprintln!("{}", "Error: --sort score needs --fuzzy, other matches have no score".red().bold());
+        std::process::exit(1);
+    }
+    if args.sort == Some(SortKey::Matches) && !args.count_lines && args.min_matches.is_none() {
+        eprintln!("{}", "Error: --sort matches needs --count-lines or --min-matches to count matching lines".red().bold());
+        std::process::exit(1);
+    }
+
+    let mut patterns = match Patterns::from_args(&args) {
+        Ok(patterns) => patterns,
+        Err(e) => {
+            eprintln!("{}", format!("Error: {}", e).red().bold());
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(range) = &args.depth_range {
+        match parse_depth_range(range) {
+            Ok(range) => patterns.depth_range = Some(range),
+            Err(e) => {
+                eprintln!("{}", format!("Error: {}", e).red().bold());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let raw_dirs = args.dir.iter().cloned()
+        .chain(args.additional_dirs.iter().map(|d| d.to_string_lossy().into_owned()));
+    let mut directories: Vec<PathBuf> = Vec::new();
+    for dir in raw_dirs {
+        match expand_path(&dir) {
+            Ok(expanded) => directories.push(PathBuf::from(expanded)),
+            Err(e) => {
+                eprintln!("{}", format!("Error: {}", e).red().bold());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // If no directories are specified, use the current directory
+    if directories.is_empty() {
+        directories.push(PathBuf::from("."));
+    }
+
+    // Symlinked roots are resolved up front, so that only the links below them are skipped
+    if args.follow_only_top {
+        for dir in &mut directories {
+            if dir.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink()) {
+                if let Ok(target) = fs::canonicalize(&*dir) {
+                    *dir = target;
+                }
+            }
+        }
+    }
+
+    if args.show_config_json {
+        let config = EffectiveConfig { directories: &directories, options: &args };
+        let written = serde_json::to_string_pretty(&config).map_err(io::Error::other)
+            .and_then(|json| writeln!(io::stdout(), "{}", json));
+        if let Err(e) = written {
+            eprintln!("{}", format!("Error writing the options: {}", e).red().bold());
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => {
+            // Escape codes only make sense on a terminal
+            colored::control::set_override(false);
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(args.append)
+                .truncate(!args.append)
+                .open(path);
+            match file {
+                Ok(file) => Box::new(BufWriter::new(file)),
+                Err(e) => {
+                    eprintln!("{}", format!("Error: cannot open {}: {}", path.display(), e).red().bold());
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => Box::new(io::stdout()),
+    };
+    if let Some(limit) = args.max_output_bytes {
+        out = Box::new(CappedWriter { inner: out, remaining: limit, truncated: false, line_start: true });
+    }
+
+    // The JSON report is a second sink, written once the results are known
+    let mut json_report = match &args.json_report {
+        Some(path) => match File::create(path) {
+            Ok(file) => Some(BufWriter::new(file)),
+            Err(e) => {
+                eprintln!("{}", format!("Error: cannot open {}: {}", path.display(), e).red().bold());
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let mut match_export = match &args.export_matches {
+        Some(path) => {
+            let Some(format) = ExportFormat::for_path(path) else {
+                eprintln!("{}", format!("Error: --export-matches needs a .json, .csv or .txt file, not {}", path.display()).red().bold());
+                std::process::exit(1);
+            };
+            match File::create(path) {
+                Ok(file) => Some((BufWriter::new(file), format)),
+                Err(e) => {
+                    eprintln!("{}", format!("Error: cannot open {}: {}", path.display(), e).red().bold());
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    if args.clear_cache {
+        if let Err(e) = DirCache::clear() {
+            eprintln!("{}", format!("Error clearing the cache: {}", e).red());
+        }
+    }
+
+    let last_run = if args.since_last_run || args.reset_last_run { LastRun::for_current_args() } else { None };
+    if args.reset_last_run {
+        if let Some(Err(e)) = last_run.as_ref().map(LastRun::clear) {
+            eprintln!("{}", format!("Error resetting the last run: {}", e).red());
+        }
+    }
+    if args.since_last_run {
+        match &last_run {
+            Some(last_run) => patterns.modified_after = last_run.previous(),
+            None => eprintln!("{}", "Warning: no state directory, --since-last-run reports every file".yellow()),
+        }
+    }
+    let run_started = SystemTime::now();
+
+    let mut stats = SearchStats::default();
+    if args.warm && args.paths_from.is_none() {
+        let warm_started = Instant::now();
+        // Nothing is sent on this channel, and errors are left for the real search to report
+        let (results, _events) = mpsc::sync_channel(0);
+        for (root, dir) in directories.iter().enumerate() {
+            let mut ctx = SearchContext { root, results: results.clone(), cache: None, warm_only: true, ignores: Vec::new(), io_limit: None,
+                root_device: args.same_filesystem.then(|| device_id(dir)).flatten(), deadline: None, fs_type_allowed: HashMap::new(), depth: 0, file_details: false, link_targets: Vec::new(), pruned: false,
+                content_pool: None, next_seq: 0 };
+            search_files(dir, &mut ctx, &args, &patterns);
+        }
+        stats.warm_elapsed = Some(warm_started.elapsed());
+    }
+
+    let started = Instant::now();
+    let deadline = args.deadline.map(|budget| started + budget);
+    let root_count = if args.paths_from.is_some() { 1 } else { directories.len() };
+    let mut root_files: Vec<Vec<SearchResult>> = (0..root_count).map(|_| Vec::new()).collect();
+    let mut root_errors: Vec<(Vec<String>, bool, String)> = (0..root_count).map(|_| Default::default()).collect();
+    // Sorting, --top-largest, --json-report, --export-matches and --blame need every result, otherwise JSON lines are printed as soon as they arrive
+    let streaming = matches!(args.format, OutputFormat::Jsonl | OutputFormat::JsonlFull) && args.sort.is_none() && args.top_largest.is_none() && args.max_per_ext.is_none() && args.sample.is_none() && !args.skip_seen && !args.strict_utf8 &&
+        args.json_report.is_none() && args.export_matches.is_none() && !args.blame;
+    let mut write_error = None;
+    let mut reservoir = args.sample.map(|capacity| Reservoir::new(capacity, args.seed));
+
+    let cwd = if args.cwd_relative { std::env::current_dir().ok() } else { None };
+    let annotate = |result: &mut SearchResult| if let Some(cwd) = &cwd {
+        result.path = cwd_relative(&result.path, cwd);
+    };
+
+    let (results, events) = mpsc::sync_channel(RESULT_CHANNEL_CAPACITY);
+    let next_root = AtomicUsize::new(0);
+    let io_limit = args.io_concurrency.map(|permits| Arc::new(IoLimiter::new(permits)));
+    // With `--content-jobs`, the search threads only walk the trees and queue the files
+    let (jobs, job_queue) = mpsc::sync_channel(RESULT_CHANNEL_CAPACITY);
+    let job_queue = Mutex::new(job_queue);
+    let reorder_slots = Arc::new(IoLimiter::new(REORDER_CAPACITY));
+    let content_pool = args.content_jobs.map(|_| ContentPool { jobs, reorder_slots: reorder_slots.clone() });
+    // Per root, what came out of the files searched ahead of their turn, and the position printed next
+    let mut reorder: Vec<BTreeMap<usize, _>> = (0..root_count).map(|_| BTreeMap::new()).collect();
+    let mut next_seq = vec![0; root_count];
+    let mut job_errors: Vec<Vec<String>> = (0..root_count).map(|_| Vec::new()).collect();
+    thread::scope(|scope| {
+        for _ in 0..args.content_jobs.unwrap_or(0) {
+            let results = results.clone();
+            let (args, patterns, job_queue, io_limit) = (&args, &patterns, &job_queue, &io_limit);
+            scope.spawn(move || content_worker(job_queue, &results, io_limit.as_deref(), deadline, args, patterns));
+        }
+        let workers = args.threads.min(root_count).max(1);
+        for _ in 0..workers {
+            let (results, content_pool) = (results.clone(), content_pool.clone());
+            let (args, patterns, directories, next_root, io_limit) = (&args, &patterns, &directories, &next_root, &io_limit);
+            scope.spawn(move || loop {
+                let root = next_root.fetch_add(1, Ordering::SeqCst);
+                if root >= root_count {
+                    break;
+                }
+                // `--paths-from` searches a single list that has no directory of its own
+                let dir = directories.get(root).map_or(Path::new("."), PathBuf::as_path);
+                search_root(root, dir, &results, io_limit.as_ref(), deadline, content_pool.as_ref(), args, patterns);
+            });
+        }
+        // The workers stop once the search threads are done and the queue is empty
+        drop(results);
+        drop(content_pool);
+
+        let mut deliver = |root: usize, mut result: SearchResult| {
+            if let Some(reservoir) = reservoir.as_mut() {
+                reservoir.offer(root, result);
+                return;
+            }
+            annotate(&mut result);
+            if !streaming {
+                root_files[root].push(result);
+            } else if let Err(e) = emit_json_line(&mut out, Path::new(&result.path), &result) {
+                write_error.get_or_insert(e);
+            }
+        };
+        for event in events {
+            match event {
+                SearchEvent::Match(root, result) => deliver(root, *result),
+                SearchEvent::Ordered { root, seq, results, errors } => {
+                    reorder[root].insert(seq, (results, errors));
+                    while let Some((results, errors)) = reorder[root].remove(&next_seq[root]) {
+                        next_seq[root] += 1;
+                        reorder_slots.give_back();
+                        for result in results {
+                            deliver(root, result);
+                        }
+                        job_errors[root].extend(errors);
+                    }
+                }
+                SearchEvent::RootDone { root, permission_denied_dirs, other_error_occurred, error_message } => {
+                    root_errors[root] = (permission_denied_dirs, other_error_occurred, error_message);
+                }
+            }
+        }
+    });
+    for ((_, other_error_occurred, error_message), errors) in root_errors.iter_mut().zip(job_errors) {
+        for e in errors {
+            *other_error_occurred = true;
+            error_message.push_str(&e);
+            error_message.push('\n');
+        }
+    }
+
+    // Nothing is printed rather than a list with names mangled or missing
+    let mut non_utf8 = NON_UTF8_PATHS.lock().unwrap_or_else(|e| e.into_inner());
+    if !non_utf8.is_empty() {
+        non_utf8.sort();
+        eprintln!("{}", format!("Error: {} path(s) are not valid UTF-8 (--strict-utf8):", non_utf8.len()).red().bold());
+        for path in non_utf8.iter() {
+            eprintln!("  {}", format!("{:?}", path).red());
+        }
+        std::process::exit(2);
+    }
+    drop(non_utf8);
+
+    if let Some(reservoir) = reservoir {
+        stats.sampled_from = Some(reservoir.seen);
+        for (root, mut result) in reservoir.into_sample() {
+            annotate(&mut result);
+            root_files[root].push(result);
+        }
+    }
+
+    if args.skip_seen {
+        stats.duplicates_collapsed += skip_seen(&mut root_files);
+    }
+
+    // Results and errors are reported in the order the roots were given
+    let mut all_files: Vec<SearchResult> = root_files.into_iter().flatten().collect();
+    let mut all_permission_denied_dirs = Vec::new();
+    let mut other_error_occurred = false;
+    let mut error_messages = String::new();
+    for (perm_denied_dirs, other_error, err_msg) in root_errors {
+        all_permission_denied_dirs.extend(perm_denied_dirs);
+        other_error_occurred |= other_error;
+        if !err_msg.is_empty() {
+            error_messages.push_str(&err_msg);
+            if !err_msg.ends_with('\n') {
+                error_messages.push('\n');
+            }
+        }
+    }
+
+    // Reported with the errors, so that it reaches every output format
+    if DEADLINE_REACHED.load(Ordering::SeqCst) {
+        other_error_occurred = true;
+        error_messages.push_str(&format!("Search stopped at the --deadline of {:?}: the results are incomplete\n",
+            args.deadline.unwrap_or_default()));
+    }
+
+    if let Some(key) = args.sort {
+        sort_results(&mut all_files, key);
+    }
+    if let Some(n) = args.top_largest {
+        keep_largest(&mut all_files, n);
+    }
+
+    if args.unique {
+        stats.duplicates_collapsed += dedup_files(&mut all_files);
+    }
+    if args.dedupe_by_name {
+        stats.duplicate_names = dedupe_by_name(&mut all_files);
+    }
+    if let Some(max) = args.max_per_ext {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        all_files.retain(|file| {
+            let count = counts.entry(extension_key(&file.path)).or_default();
+            *count += 1;
+            *count <= max
+        });
+    }
+    // Only the matches left to print are worth asking git about
+    if args.blame {
+        git_blame_all(&mut all_files);
+    }
+    stats.elapsed = started.elapsed();
+    stats.zero_byte_skipped = ZERO_BYTE_SKIPPED.load(Ordering::SeqCst);
+    stats.dirs_searched = DIRS_SEARCHED.load(Ordering::SeqCst);
+    stats.files_checked = FILES_CHECKED.load(Ordering::SeqCst);
+    let matched = all_files.len();
+    // Matches inside archives have no directory of their own to run a command in
+    let exec_dirs: Vec<String> = if args.exec_dir.is_some() {
+        let mut seen = HashSet::new();
+        all_files.iter().map(|file| parent_dir(&file.path))
+            .filter(|dir| Path::new(dir).is_dir() && seen.insert(dir.clone()))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let mut result = match write_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    };
+    if let Some(report) = &mut json_report {
+        result = result
+            .and_then(|_| write_json_report(report, &all_files, &all_permission_denied_dirs, &error_messages, args.max_error_entries,
+                args.total_matches.then(|| total_matches(&all_files))))
+            .and_then(|_| report.flush());
+    }
+    if let Some((export, format)) = &mut match_export {
+        result = result
+            .and_then(|_| write_match_export(export, *format, &all_files))
+            .and_then(|_| export.flush());
+    }
+    // Only what is printed is shortened: the reports and --exec-dir keep the full paths
+    if args.names_only || args.stems_only {
+        for file in &mut all_files {
+            file.path = file_name_only(&file.path, args.stems_only);
+        }
+        if args.unique {
+            let before = all_files.len();
+            let mut seen = HashSet::new();
+            all_files.retain(|file| seen.insert(file.path.clone()));
+            stats.duplicates_collapsed += before - all_files.len();
+        }
+    }
+    result = result.and_then(|_| display_results(&mut out, &args, &patterns, &directories, all_files, all_permission_denied_dirs, other_error_occurred, error_messages, &stats));
+
+    // A single line, with the results flushed first so that it comes last
+    if args.stats_machine {
+        result = result.and_then(|_| out.flush());
+        eprintln!("dirs={} files={} matched={} elapsed_ms={}", stats.dirs_searched, stats.files_checked, matched, stats.elapsed.as_millis());
+    }
+
+    let mut exec_failed = false;
+    if let Some(command) = &args.exec_dir {
+        result = result.and_then(|_| out.flush());
+        exec_failed = !run_exec_dir(command, &exec_dirs);
+    }
+
+    // The next run only reports what changed from the moment this one started
+    if let (true, true, Some(last_run)) = (args.since_last_run, result.is_ok(), &last_run) {
+        if let Err(e) = last_run.save(run_started) {
+            eprintln!("{}", format!("Error saving the time of this run: {}", e).red());
+        }
+    }
+
+    if args.watch && result.is_ok() {
+        result = watch_directories(&mut out, &directories, &args, &patterns);
+    }
+
+    if let Err(e) = result.and_then(|_| out.flush()) {
+        eprintln!("{}", format!("Error writing results: {}", e).red().bold());
+        std::process::exit(1);
+    }
+    if exec_failed {
+        std::process::exit(1);
+    }
+}
+
+/// Runs the `--exec-dir` command for the directories holding matches.
+///
+/// The command is run by `sh -c`, with every `{}` replaced by the shell-quoted directory, or
+/// the directory appended when there is no `{}`. A command ending with `+` is run only once,
+/// with all the directories in place of `{}`, separated by spaces.
+///
+/// # Parameters
+///
+/// * `command` - The command given to `--exec-dir`.
+/// * `dirs` - The directories, in the order their first match was reported.
+///
+/// # Returns
+///
+/// `true` if every command could be started and exited successfully; failures are reported
+/// on stderr.
+fn run_exec_dir(command: &str, dirs: &[String]) -> bool {
+    let (command, batched) = match command.trim_end().strip_suffix('+') {
+        Some(command) => (command.trim_end(), true),
+        None => (command, false),
+    };
+    let substitute = |quoted: &str| if command.contains("{}") {
+        command.replace("{}", quoted)
+    } else {
+        format!("{} {}", command, quoted)
+    };
+    let lines: Vec<String> = if batched {
+        if dirs.is_empty() {
+            return true;
+        }
+        vec![substitute(&dirs.iter().map(|dir| shell_quote(dir)).collect::<Vec<_>>().join(" "))]
+    } else {
+        dirs.iter().map(|dir| substitute(&shell_quote(dir))).collect()
+    };
+
+    let mut ok = true;
+    for line in lines {
+        match std::process::Command::new("sh").arg("-c").arg(&line).status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                ok = false;
+                eprintln!("{}", format!("Error: `{}` failed ({})", line, status).red());
+            }
+            Err(e) => {
+                ok = false;
+                eprintln!("{}", format!("Error running `{}`: {}", line, e).red());
+            }
+        }
+    }
+    ok
+}
+
+/// Watches the searched directories and prints files that match as they change.
+///
+/// Events are debounced: changed paths are collected until no event arrived for
+/// `WATCH_DEBOUNCE`, then each one is checked against the search criteria.
+/// Runs until interrupted with Ctrl-C, then prints a short summary.
+///
+/// Returns an `io::Result` reporting whether the matches could be written to `out`.
+///
+/// # Parameters
+///
+/// * `out` - The writer new matches are printed to.
+/// * `directories` - A slice of `PathBuf` representing the directories to watch.
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+fn watch_directories(out: &mut dyn Write, directories: &[PathBuf], args: &Args, patterns: &Patterns) -> io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(tx) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("{}", format!("Error: cannot watch directories: {}", e).red());
+            return Ok(());
+        }
+    };
+    for dir in directories {
+        if let Err(e) = watcher.watch(dir, RecursiveMode::Recursive) {
+            eprintln!("{}", format!("Error watching {}: {}", dir.display(), e).red());
+        }
+    }
+
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_flag = Arc::clone(&running);
+    if let Err(e) = ctrlc::set_handler(move || handler_flag.store(false, Ordering::SeqCst)) {
+        eprintln!("{}", format!("Error installing Ctrl-C handler: {}", e).red());
+    }
+
+    let mut pending: HashSet<PathBuf> = HashSet::new();
+    let mut matched: HashSet<PathBuf> = HashSet::new();
+
+    if args.format == OutputFormat::Text && !args.no_summary {
+        writeln!(out, "\n{}", "Watching for changes (Ctrl-C to stop)...".bold())?;
+        out.flush()?;
+    }
+
+    while running.load(Ordering::SeqCst) {
+        match rx.recv_timeout(WATCH_DEBOUNCE) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    pending.extend(event.paths);
+                }
+                continue;
+            }
+            Ok(Err(e)) => {
+                eprintln!("{}", format!("Error watching: {}", e).red());
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        for path in pending.drain() {
+            if !path.is_file() {
+                continue;
+            }
+            let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+
+            // Files below a pruned directory would not have been reached by the search
+            let root = directories.iter().find(|root| path.starts_with(root));
+            let in_pruned_dir = root.zip(path.parent()).is_some_and(|(root, parent)|
+                parent.strip_prefix(root).unwrap_or(parent).components()
+                    .any(|c| c.as_os_str().to_str().is_some_and(|name| prune_dir(name, args, patterns))));
+            if in_pruned_dir {
+                continue;
+            }
+
+            let (result, err) = file_matches(&path, file_name, args, patterns);
+            if let Some(e) = err {
+                eprintln!("  {}", e.red());
+            }
+            if let Some(mut result) = result {
+                if args.blame {
+                    git_blame_all(std::slice::from_mut(&mut result));
+                }
+                if args.format == OutputFormat::JsonlFull {
+                    result.details = Some(file_details(&path));
+                }
+                if args.format != OutputFormat::Text {
+                    emit_json_line(out, &path, &result)?;
+                } else if args.diff {
+                    write_diff(out, result.diff.as_deref().unwrap_or(""))?;
+                } else if args.only_matching {
+                    write_spans(out, patterns, &result)?;
+                } else if let Some(parts) = &patterns.template {
+                    writeln!(out, "{}", render_template(parts, &result.path, &result.captures))?;
+                } else if args.shell_quote {
+                    writeln!(out, "{}", shell_quote(&result.path))?;
+                } else if args.uri {
+                    writeln!(out, "{}", file_uri(&path))?;
+                } else if args.no_summary {
+                    writeln!(out, "{}", format_result(args, &result))?;
+                } else {
+                    writeln!(out, "  + {}", format_result(args, &result))?;
+                }
+                matched.insert(path);
+            }
+        }
+        out.flush()?;
+    }
+
+    if args.format == OutputFormat::Text {
+        writeln!(out, "\n{}", "Watch stopped.".bold())?;
+        writeln!(out, "  {} file(s) matched while watching.", matched.len())?;
+    }
+    Ok(())
+}
+
+/// Default for `--threads`: the number of CPUs, or 1 if it can't be determined.
+fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Checks a file against the search criteria, and the members of a `.zip` archive too.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file.
+/// * `file_name` - The name of the file (its last path component).
+/// * `depth` - Depth of the file below its root, recorded in the results with `--depth-histogram`.
+/// * `archives` - Whether to search the members of the file if it is a `.zip` archive.
+/// * `io_limit` - The `IoLimiter` to take a permit from for each read, with `--io-concurrency`.
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+///
+/// # Returns
+///
+/// The matches, the file first, and the errors met while reading it.
+fn check_file(path: &Path, file_name: &str, depth: Option<usize>, archives: bool, io_limit: Option<&IoLimiter>, args: &Args, patterns: &Patterns) -> (Vec<SearchResult>, Vec<String>) {
+    let permit = io_limit.map(IoLimiter::acquire);
+    FILES_CHECKED.fetch_add(1, Ordering::SeqCst);
+    let (result, err) = file_matches(path, file_name, args, patterns);
+    drop(permit);
+    let mut results: Vec<SearchResult> = result.into_iter().collect();
+    let mut errors: Vec<String> = err.into_iter().collect();
+
+    if archives && is_zip_archive(path) {
+        let permit = io_limit.map(IoLimiter::acquire);
+        let (members, member_errors) = search_archive(path, args, patterns);
+        drop(permit);
+        results.extend(members);
+        errors.extend(member_errors);
+    }
+    // Archive members are counted at the depth of their archive
+    for result in &mut results {
+        result.depth = depth;
+    }
+    (results, errors)
+}
+
+/// Searches the files handed over by the search threads, on a `--content-jobs` worker.
+///
+/// Every job is answered, even past the deadline, so that the printer never waits for a
+/// position in the traversal order that would not come.
+///
+/// # Parameters
+///
+/// * `jobs` - The queue of files, shared by the workers.
+/// * `results` - The channel to the printer.
+/// * `io_limit` - The `IoLimiter` shared by all search threads, with `--io-concurrency`.
+/// * `deadline` - When every search thread has to stop, with `--deadline`.
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+fn content_worker(jobs: &Mutex<mpsc::Receiver<ContentJob>>, results: &mpsc::SyncSender<SearchEvent>, io_limit: Option<&IoLimiter>, deadline: Option<Instant>, args: &Args, patterns: &Patterns) {
+    loop {
+        let job = jobs.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        let Ok(job) = job else { break };
+        let (mut found, errors) = match job.path.file_name().and_then(|n| n.to_str()) {
+            _ if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                DEADLINE_REACHED.store(true, Ordering::SeqCst);
+                (Vec::new(), Vec::new())
+            }
+            Some(file_name) => check_file(&job.path, file_name, job.depth, job.archives, io_limit, args, patterns),
+            None => (Vec::new(), Vec::new()),
+        };
+        if args.format == OutputFormat::JsonlFull {
+            for result in &mut found {
+                result.details = Some(file_details(Path::new(&result.path)));
+            }
+        }
+        let _ = results.send(SearchEvent::Ordered { root: job.root, seq: job.seq, results: found, errors });
+    }
+}
+
+/// Searches one root (or the `--paths-from` list) on a search thread.
+///
+/// Matches are sent to the printer as they are found, followed by a `RootDone`
+/// event carrying the errors of this root.
+///
+/// # Parameters
+///
+/// * `root` - Index of the search root, attached to its results.
+/// * `dir` - A reference to the `Path` of the search root.
+/// * `results` - The channel to the printer.
+/// * `io_limit` - The `IoLimiter` shared by all search threads, with `--io-concurrency`.
+/// * `deadline` - When every search thread has to stop, with `--deadline`.
+/// * `content_pool` - The `--content-jobs` workers to hand the files to.
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+#[allow(clippy::too_many_arguments)]
+fn search_root(root: usize, dir: &Path, results: &mpsc::SyncSender<SearchEvent>, io_limit: Option<&Arc<IoLimiter>>, deadline: Option<Instant>, content_pool: Option<&ContentPool>, args: &Args, patterns: &Patterns) {
+    let mut ctx = SearchContext {
+        root,
+        results: results.clone(),
+        cache: if args.cache && args.paths_from.is_none() { DirCache::load(dir) } else { None },
+        warm_only: false,
+        ignores: Vec::new(),
+        io_limit: io_limit.cloned(),
+        root_device: args.same_filesystem.then(|| device_id(dir)).flatten(),
+        deadline,
+        fs_type_allowed: HashMap::new(),
+        depth: 0,
+        file_details: args.format == OutputFormat::JsonlFull,
+        link_targets: Vec::new(),
+        pruned: false,
+        content_pool: content_pool.cloned(),
+        next_seq: 0,
+    };
+
+    let (permission_denied_dirs, mut other_error_occurred, mut error_message) = match &args.paths_from {
+        Some(list) => check_listed_paths(list, &mut ctx, args, patterns),
+        None => search_files(dir, &mut ctx, args, patterns),
+    };
+    if let Some(Err(e)) = ctx.cache.as_ref().map(DirCache::save) {
+        other_error_occurred = true;
+        error_message.push_str(&format!("Error saving the cache for {}: {}", dir.display(), e));
+    }
+
+    let _ = results.send(SearchEvent::RootDone { root, permission_denied_dirs, other_error_occurred, error_message });
+}
+
+/// Sorts the results by the given key, falling back to the path to break ties.
+///
+/// # Parameters
+///
+/// * `files` - The list of matching files, sorted in place.
+/// * `key` - The `SortKey` chosen with `--sort`.
+fn sort_results(files: &mut [SearchResult], key: SortKey) {
+    match key {
+        SortKey::Name => files.sort_by(|a, b| {
+            let name = |file: &SearchResult| Path::new(&file.path).file_name().map(|n| n.to_os_string());
+            name(a).cmp(&name(b)).then_with(|| a.path.cmp(&b.path))
+        }),
+        SortKey::Path => files.sort_by(|a, b| a.path.cmp(&b.path)),
+        SortKey::Size => files.sort_by_cached_key(|file| (fs::metadata(&file.path).map_or(0, |m| m.len()), file.path.clone())),
+        SortKey::Mtime => files.sort_by_cached_key(|file| {
+            (fs::metadata(&file.path).and_then(|m| m.modified()).unwrap_or(UNIX_EPOCH), file.path.clone())
+        }),
+        SortKey::Score => files.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.path.cmp(&b.path))),
+        SortKey::Matches => files.sort_by(|a, b| b.matching_lines.cmp(&a.matching_lines).then_with(|| a.path.cmp(&b.path))),
+    }
+}
+
+/// Keeps only the `n` largest files, sorted from largest to smallest.
+///
+/// Most results are discarded by a linear-time selection before the rest is sorted,
+/// which matters when `n` is small and the result set is large. Ties are broken by path.
+///
+/// # Parameters
+///
+/// * `files` - The list of matching files, with their `size` filled in.
+/// * `n` - The number of files to keep.
+fn keep_largest(files: &mut Vec<SearchResult>, n: usize) {
+    let largest_first = |a: &SearchResult, b: &SearchResult| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path));
+    if n == 0 {
+        files.clear();
+        return;
+    }
+    if files.len() > n {
+        files.select_nth_unstable_by(n - 1, largest_first);
+        files.truncate(n);
+    }
+    files.sort_by(largest_first);
+}
+
+/// Applies the search criteria to the file paths listed in a file, without walking directories.
+///
+/// # Parameters
+///
+/// * `list` - A reference to the `Path` of the list, one path per line, or `-` for stdin.
+/// * `ctx` - The `SearchContext` matching files are sent to.
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+///
+/// # Returns
+///
+/// The same tuple as `search_files`: paths where permission was denied,
+/// whether any other error occurred, and the error messages.
+fn check_listed_paths(list: &Path, ctx: &mut SearchContext, args: &Args, patterns: &Patterns) -> (Vec<String>, bool, String) {
+    let mut permission_denied = Vec::new();
+    let mut other_error_occurred = false;
+    let mut error_message = String::new();
+
+    let reader: Box<dyn BufRead> = if list == Path::new("-") {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        match File::open(list) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                return (permission_denied, true, format!("Error reading path list {}: {}", list.display(), error_detail(&e, args)));
+            }
+        }
+    };
+
+    for line in reader.lines() {
+        if ctx.past_deadline() {
+            break;
+        }
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                other_error_occurred = true;
+                error_message.push_str(&format!("Error reading path list {}: {}\n", list.display(), error_detail(&e, args)));
+                break;
+            }
+        };
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+
+        let path = PathBuf::from(line);
+        match path.metadata() {
+            Ok(metadata) if metadata.is_dir() => {
+                other_error_occurred = true;
+                error_message.push_str(&format!("Error: {} is a directory\n", path.display()));
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                permission_denied.push(path.to_string_lossy().into_owned());
+                continue;
+            }
+            Err(e) => {
+                other_error_occurred = true;
+                error_message.push_str(&format!("Error accessing {}: {}\n", path.display(), error_detail(&e, args)));
+                continue;
+            }
+        }
+
+        let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if ctx.content_pool.is_some() {
+            ctx.queue_file(path, None, false);
+            continue;
+        }
+        let (results, errors) = check_file(&path, file_name, None, false, ctx.io_limit.as_deref(), args, patterns);
+        for e in errors {
+            other_error_occurred = true;
+            error_message.push_str(&e);
+            error_message.push('\n');
+        }
+        for result in results {
+            ctx.emit(result);
+        }
+    }
+
+    (permission_denied, other_error_occurred, error_message)
+}
+
+/// Removes files that resolve to the same canonical path, keeping the first occurrence.
+///
+/// Paths that cannot be canonicalized are compared as given.
+///
+/// # Parameters
+///
+/// * `files` - The list of matching files, deduplicated in place.
+///
+/// # Returns
+///
+/// The number of duplicate entries that were removed.
+fn dedup_files(files: &mut Vec<SearchResult>) -> usize {
+    let before = files.len();
+    let mut seen = HashSet::new();
+    files.retain(|file| {
+        let key = fs::canonicalize(&file.path).unwrap_or_else(|_| PathBuf::from(&file.path));
+        seen.insert(key)
+    });
+    before - files.len()
+}
+
+/// A fixed-size uniform sample of the matches, for `--sample`.
+///
+/// Reservoir sampling: the `n`-th match offered replaces a random entry with probability
+/// `capacity / n`, so memory stays bounded however many files match.
+struct Reservoir {
+    capacity: usize,
+    /// Number of matches offered so far.
+    seen: usize,
+    /// The sampled matches, with the order they were offered in and their search root.
+    items: Vec<(usize, usize, SearchResult)>,
+    rng: StdRng,
+}
+
+impl Reservoir {
+    /// Creates an empty sample, seeded from the system when `seed` is `None`.
+    fn new(capacity: usize, seed: Option<u64>) -> Reservoir {
+        let rng = seed.map_or_else(rand::make_rng, StdRng::seed_from_u64);
+        Reservoir { capacity, seen: 0, items: Vec::new(), rng }
+    }
+
+    /// Considers a match for the sample.
+    fn offer(&mut self, root: usize, result: SearchResult) {
+        let index = self.seen;
+        self.seen += 1;
+        if self.items.len() < self.capacity {
+            self.items.push((index, root, result));
+        } else {
+            let slot = self.rng.random_range(0..self.seen);
+            if slot < self.capacity {
+                self.items[slot] = (index, root, result);
+            }
+        }
+    }
+
+    /// The sampled matches with their search root, in the order they were found.
+    fn into_sample(mut self) -> impl Iterator<Item = (usize, SearchResult)> {
+        self.items.sort_by_key(|(index, _, _)| *index);
+        self.items.into_iter().map(|(_, root, result)| (root, result))
+    }
+}
+
+/// Removes the files already found under an earlier search root, for `--skip-seen`.
+///
+/// Files are compared by canonical path, or as given when that fails. A file found twice
+/// under the same root is kept both times.
+///
+/// # Parameters
+///
+/// * `root_files` - The matching files of each search root, in the order the roots were given.
+///
+/// # Returns
+///
+/// The number of entries that were removed.
+fn skip_seen(root_files: &mut [Vec<SearchResult>]) -> usize {
+    let mut seen = HashSet::new();
+    let mut removed = 0;
+    for files in root_files {
+        // Only added once the whole root is done, so that its own duplicates stay
+        let mut kept = Vec::new();
+        let before = files.len();
+        files.retain(|file| {
+            let key = fs::canonicalize(&file.path).unwrap_or_else(|_| PathBuf::from(&file.path));
+            let keep = !seen.contains(&key);
+            if keep {
+                kept.push(key);
+            }
+            keep
+        });
+        removed += before - files.len();
+        seen.extend(kept);
+    }
+    removed
+}
+
+/// Removes the files whose name was already seen, keeping the first occurrence.
+///
+/// Names are compared exactly, so `Settings.toml` and `settings.toml` are different names.
+///
+/// # Parameters
+///
+/// * `files` - The list of matching files, deduplicated in place.
+///
+/// # Returns
+///
+/// One entry per name found more than once, in the order of the first occurrences: the name,
+/// the path that was kept and the paths that were removed.
+fn dedupe_by_name(files: &mut Vec<SearchResult>) -> Vec<(String, String, Vec<String>)> {
+    let mut groups: Vec<(String, String, Vec<String>)> = Vec::new();
+    let mut first_of: HashMap<String, usize> = HashMap::new();
+    files.retain(|file| {
+        let name = Path::new(&file.path).file_name().map_or_else(|| file.path.clone(), |n| n.to_string_lossy().into_owned());
+        match first_of.get(&name) {
+            Some(&group) => {
+                groups[group].2.push(file.path.clone());
+                false
+            }
+            None => {
+                first_of.insert(name.clone(), groups.len());
+                groups.push((name, file.path.clone(), Vec::new()));
+                true
+            }
+        }
+    });
+    groups.retain(|(_, _, later)| !later.is_empty());
+    groups
+}
+
+/// Expands a leading `~` and `$VAR` / `${VAR}` references in a directory argument.
+///
+/// Quoted arguments are not expanded by the shell, so this is done here instead. `~` is the
+/// home directory of the platform, found even when `HOME` is not set, and may also be
+/// followed by `\` on Windows.
+///
+/// # Parameters
+///
+/// * `input` - The directory argument as given on the command line.
+///
+/// # Returns
+///
+/// A `Result` containing:
+/// * `Ok(String)` - The argument with every reference replaced by its value.
+/// * `Err(String)` - A message naming the variable that is not set, or telling that the home
+///   directory is unknown.
+///
+/// # Example
+///
+/// ```
+/// let dir = expand_path("$HOME/projects")?; // "/home/user/projects"
+/// ```
+fn expand_path(input: &str) -> Result<String, String> {
+    let lookup = |name: &str| std::env::var(name)
+        .map_err(|_| format!("environment variable {} is not set (in '{}')", name, input));
+
+    let mut expanded = String::new();
+    let mut rest = input;
+
+    if rest == "~" || rest.starts_with("~/") || (cfg!(windows) && rest.starts_with("~\\")) {
+        let home = dirs::home_dir().ok_or_else(|| format!("the home directory is unknown (in '{}')", input))?;
+        expanded.push_str(&home.to_string_lossy());
+        rest = &rest[1..];
+    }
+
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+
+        let mut name = String::new();
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            let mut closed = false;
+            for c in chars.by_ref() {
+                if c == '}' {
+                    closed = true;
+                    break;
+                }
+                name.push(c);
+            }
+            if !closed {
+                return Err(format!("unterminated '${{' in '{}'", input));
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_alphanumeric() || c == '_' {
+                    name.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+        }
+
+        if name.is_empty() {
+            // A lone `$` is kept as-is
+            expanded.push('$');
+        } else {
+            expanded.push_str(&lookup(&name)?);
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// Parses a size such as `512`, `500K`, `10M` or `1G`.
+///
+/// Units are powers of 1024 and case-insensitive; a bare number is read as bytes.
+///
+/// # Parameters
+///
+/// * `input` - The size as given on the command line.
+///
+/// # Returns
+///
+/// A `Result` containing the size in bytes, or a message describing why it is invalid.
+fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let split = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+    let (amount, unit) = input.split_at(split);
+
+    let amount: u64 = amount.parse()
+        .map_err(|_| format!("invalid size '{}': expected a number followed by K, M or G", input))?;
+    let factor: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("invalid size unit '{}': expected K, M or G", unit)),
+    };
+
+    amount.checked_mul(factor).ok_or_else(|| format!("size '{}' is too large", input))
+}
+
+/// Parses a calendar date written as `YYYY-MM-DD`.
+///
+/// # Parameters
+///
+/// * `input` - The date as given on the command line.
+///
+/// # Returns
+///
+/// A `Result` containing the parsed date, or a message describing why it is invalid.
+fn parse_date(input: &str) -> Result<chrono::NaiveDate, String> {
+    chrono::NaiveDate::parse_from_str(input.trim(), "%Y-%m-%d")
+        .map_err(|e| format!("invalid date '{}': {} (expected YYYY-MM-DD)", input, e))
+}
+
+/// Parses the size given to `--read-buffer-size`, which can't be zero.
+///
+/// # Parameters
+///
+/// * `input` - The size as given on the command line.
+///
+/// # Returns
+///
+/// A `Result` containing the buffer size in bytes, or a message describing why it is invalid.
+fn parse_buffer_size(input: &str) -> Result<usize, String> {
+    match parse_size(input)? {
+        0 => Err(String::from("the read buffer can't be empty")),
+        size => usize::try_from(size).map_err(|_| format!("size '{}' is too large", input)),
+    }
+}
+
+/// Parses the fraction given to `--min-match-ratio`, from 0 to 1 inclusive.
+///
+/// # Parameters
+///
+/// * `input` - The fraction as given on the command line.
+///
+/// # Returns
+///
+/// A `Result` containing the fraction, or a message describing why it is invalid.
+fn parse_ratio(input: &str) -> Result<f64, String> {
+    match input.trim().parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        Ok(_) => Err(format!("ratio '{}' is not between 0.0 and 1.0", input)),
+        Err(e) => Err(format!("invalid ratio '{}': {}", input, e)),
+    }
+}
+
+/// Parses a duration such as `500ms`, `90s`, `15m`, `12h`, `7d` or `2w`.
+///
+/// A bare number is read as seconds.
+///
+/// # Parameters
+///
+/// * `input` - The duration as given on the command line.
+///
+/// # Returns
+///
+/// A `Result` containing the parsed `Duration`, or a message describing why it is invalid.
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let split = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+    let (amount, unit) = input.split_at(split);
+
+    let amount: u64 = amount.parse()
+        .map_err(|_| format!("invalid duration '{}': expected a number followed by ms, s, m, h, d or w", input))?;
+    if unit == "ms" {
+        return Ok(Duration::from_millis(amount));
+    }
+    let factor = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("invalid duration unit '{}': expected ms, s, m, h, d or w", unit)),
+    };
+
+    amount.checked_mul(factor)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{}' is too large", input))
+}
+
+/// Parses a `--filter-by-depth-range` such as `2..4`, `2..=4`, `2..` or `..=4`.
+///
+/// The end of `a..b` is excluded and the end of `a..=b` included, as in Rust; a missing start
+/// is 1, the entries directly in the search root, and a missing end means no limit.
+///
+/// # Parameters
+///
+/// * `input` - The range as given on the command line.
+///
+/// # Returns
+///
+/// A `Result` containing the smallest depth and the largest one, if any, or a message
+/// describing why the range is invalid.
+fn parse_depth_range(input: &str) -> Result<(usize, Option<usize>), String> {
+    let invalid = || format!("invalid depth range '{}': expected a..b, a..=b, a.. or ..=b", input);
+    let (start, end) = input.trim().split_once("..").ok_or_else(invalid)?;
+    let (end, inclusive) = match end.strip_prefix('=') {
+        Some(end) => (end, true),
+        None => (end, false),
+    };
+    let bound = |text: &str| if text.is_empty() { Ok(None) } else { text.parse::<usize>().map(Some).map_err(|_| invalid()) };
+
+    let start = bound(start)?.unwrap_or(1);
+    let end = match bound(end)? {
+        Some(end) if inclusive => Some(end),
+        Some(end) => Some(end.checked_sub(1).ok_or_else(|| format!("depth range '{}' is empty", input))?),
+        None if inclusive => return Err(invalid()),
+        None => None,
+    };
+    if end.is_some_and(|end| end < start) {
+        return Err(format!("depth range '{}' is empty", input));
+    }
+    Ok((start, end))
+}
+
+/// Describes an I/O error, adding its kind at the highest verbosity (`-vv`).
+///
+/// # Parameters
+///
+/// * `e` - A reference to the `io::Error` to describe.
+/// * `args` - A reference to `Args` carrying the verbosity.
+///
+/// # Returns
+///
+/// The error message, e.g. `No such file or directory (os error 2) [NotFound]` at `-vv`.
+fn error_detail(e: &io::Error, args: &Args) -> String {
+    if args.verbose >= 2 {
+        format!("{} [{:?}]", e, e.kind())
+    } else {
+        e.to_string()
+    }
+}
+
+/// Checks whether a file timestamp lies within `window` of the current time.
+///
+/// Timestamps that could not be read are treated as not matching.
+///
+/// # Parameters
+///
+/// * `time` - The timestamp read from the file's metadata.
+/// * `window` - How far back from now the timestamp may be.
+///
+/// # Returns
+///
+/// `true` if the timestamp is no older than `window`, `false` otherwise.
+fn is_within(time: io::Result<SystemTime>, window: Duration) -> bool {
+    match time {
+        // Timestamps in the future count as recent
+        Ok(time) => SystemTime::now().duration_since(time).map_or(true, |age| age <= window),
+        Err(_) => false,
+    }
+}
+
+/// Searches for files in the specified directory based on given criteria.
+///
+/// # Parameters
+///
+/// * `dir` - A reference to a `Path` representing the directory to search in.
+/// * `ctx` - The `SearchContext` matching files are sent to, holding the cache of this search root.
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+///
+/// # Returns
+///
+/// A tuple containing:
+/// * `Vec<String>` - A list of directories where permission was denied.
+/// * `bool` - Indicates if any other errors occurred during the search.
+/// * `String` - Contains error messages, if any.
+///
+/// # Example
+///
+/// ```
+/// let args = Args { /* ... */ };
+/// let patterns = Patterns::from_args(&args)?;
+/// let (denied_dirs, has_errors, error_msg) = search_files(Path::new("/home/user"), &mut ctx, &args, &patterns);
+/// ```
+fn search_files(dir: &Path, ctx: &mut SearchContext, args: &Args, patterns: &Patterns) -> (Vec<String>, bool, String) {
+    let mut permission_denied_dirs = Vec::new();
+    let mut other_error_occurred = false;
+    let mut error_message = String::new();
+
+    // Check if the path is a directory
+    let dir_modified = match dir.metadata() {
+        Ok(metadata) => {
+            if !metadata.is_dir() {
+                other_error_occurred = true;
+                error_message = format!("Error: {} is not a directory", dir.display());
+                return (permission_denied_dirs, other_error_occurred, error_message);
+            }
+            metadata.modified().ok()
+        },
+        Err(e) => {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                permission_denied_dirs.push(dir.to_string_lossy().into_owned());
+                return (permission_denied_dirs, other_error_occurred, error_message);
+            } else {
+                other_error_occurred = true;
+                error_message = format!("Error accessing {}: {}", dir.display(), error_detail(&e, args));
+                return (permission_denied_dirs, other_error_occurred, error_message);
+            }
+        }
+    };
+
+    let cached = dir_modified.zip(ctx.cache.as_mut())
+        .and_then(|(modified, cache)| cache.lookup(dir, modified));
+    let mut entries: Vec<io::Result<PathBuf>> = match cached {
+        Some(names) => names.into_iter().map(|name| Ok(dir.join(name))).collect(),
+        None => {
+            let permit = ctx.io_permit();
+            let read_dir = match fs::read_dir(dir) {
+                Ok(rd) => rd,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::PermissionDenied {
+                        permission_denied_dirs.push(dir.to_string_lossy().into_owned());
+                        return (permission_denied_dirs, other_error_occurred, error_message);
+                    } else {
+                        other_error_occurred = true;
+                        error_message = format!("Error reading directory {}: {}", dir.display(), error_detail(&e, args));
+                        return (permission_denied_dirs, other_error_occurred, error_message);
+                    }
+                }
+            };
+
+            let entries: Vec<io::Result<PathBuf>> = read_dir.map(|entry| entry.map(|e| e.path())).collect();
+            drop(permit);
+            if let Some((modified, cache)) = dir_modified.zip(ctx.cache.as_mut()) {
+                cache.store(dir, modified, &entries);
+            }
+            entries
+        }
+    };
+    if !ctx.warm_only {
+        DIRS_SEARCHED.fetch_add(1, Ordering::SeqCst);
+    }
+    if args.sorted_traversal {
+        // Names compare byte-wise so the order doesn't depend on the locale; unreadable entries go last
+        entries.sort_by(|a, b| match (a, b) {
+            (Ok(a), Ok(b)) => a.file_name().cmp(&b.file_name()),
+            (a, b) => b.is_ok().cmp(&a.is_ok()),
+        });
+    }
+    if args.depth {
+        // Post-order: everything below a subdirectory is reported before the files next to it
+        let (dirs, others): (Vec<_>, Vec<_>) = entries.into_iter().partition(|entry| entry.as_ref().is_ok_and(|path| path.is_dir()));
+        entries = dirs;
+        entries.extend(others);
+    }
+
+    // The rules of this directory apply to everything below it, until it has been searched
+    let ripgrep_names = if args.use_ignore_files { &RIPGREP_IGNORE_FILE_NAMES[..] } else { &[] };
+    let own_name = if args.no_findfileignore { None } else { Some(IGNORE_FILE_NAME) };
+    let outer_ignores = ctx.ignores.len();
+    ctx.ignores.extend(ripgrep_names.iter().copied().chain(own_name).filter_map(|name| IgnoreFile::load(dir, name)));
+
+    // The listing tells whether this directory is a leaf, before anything is pruned
+    let mut own_result = None;
+    if args.leaf_dirs && !ctx.warm_only && !ctx.pruned && depth_in_range(ctx.depth, patterns) && !entries.iter().any(|entry| entry.as_ref().is_ok_and(|path| path.is_dir())) {
+        let name = dir.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let (name_matches, score) = name_pattern_matches(name, args, patterns);
+        if name.is_empty() || (name_matches && required_name_matches(name, args, patterns)) {
+            own_result = Some(SearchResult { path: dir.to_string_lossy().into_owned(), score, depth: args.depth_histogram.then_some(ctx.depth), ..SearchResult::default() });
+        }
+    }
+
+    // Like leaf directories, the files looked for count even when they are hidden or ignored
+    if let Some(wanted) = patterns.contains_file.as_ref().filter(|_| !ctx.warm_only && !ctx.pruned && depth_in_range(ctx.depth, patterns)) {
+        let holds_file = entries.iter().flatten().any(|path| path.is_file() &&
+            path.file_name().and_then(|n| n.to_str()).is_some_and(|name| wanted.is_match(name)));
+        let name = dir.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let (name_matches, score) = name_pattern_matches(name, args, patterns);
+        if holds_file && (name.is_empty() || (name_matches && required_name_matches(name, args, patterns))) {
+            own_result = Some(SearchResult { path: dir.to_string_lossy().into_owned(), score, depth: args.depth_histogram.then_some(ctx.depth), ..SearchResult::default() });
+        }
+    }
+    // With `--depth`, the directory itself comes after everything below it
+    if !args.depth {
+        if let Some(result) = own_result.take() {
+            ctx.emit(result);
+        }
+    }
+
+    // With `--dir-hit`, the first matching file settles this directory
+    let mut dir_hit = false;
+
+    for entry in entries {
+        if ctx.past_deadline() {
+            break;
+        }
+        match entry {
+            Ok(path) => {
+                if args.strict_utf8 && !ctx.warm_only && path.file_name().is_some_and(|name| name.to_str().is_none()) {
+                    NON_UTF8_PATHS.lock().unwrap_or_else(|e| e.into_inner()).push(path);
+                    continue;
+                }
+                let is_dir = path.is_dir();
+                // Forced entries are checked first, since no exclusion applies to them
+                let forced = patterns.force_included(&path, is_dir);
+                let excluded = !forced && (ctx.pruned || ctx.is_ignored(&path, is_dir) || (is_dir &&
+                    path.file_name().and_then(|n| n.to_str()).is_some_and(|name| prune_dir(name, args, patterns))));
+                // Excluded directories are still walked for the forced paths that could be below them
+                if excluded && !(is_dir && patterns.force_include.iter().any(|rule| rule.anchored)) {
+                    continue;
+                }
+
+                // With --follow-only-top, symlinked directories (and junctions) below the roots are neither
+                // searched nor reported; otherwise they are followed like any directory
+                let is_dir_link = is_dir && is_link(&path);
+                if is_dir_link && args.follow_only_top {
+                    continue;
+                }
+
+                if is_dir {
+                    // Mount points are skipped, like with find -xdev
+                    if ctx.root_device.is_some_and(|root_device| device_id(&path).is_some_and(|device| device != root_device)) {
+                        continue;
+                    }
+                    if !args.fs_type.is_empty() && !ctx.fs_type_matches(&path, &args.fs_type) {
+                        continue;
+                    }
+                    // Symlinks to directories are reported and still followed
+                    if patterns.links_to.as_deref().is_some_and(|target| !ctx.warm_only && !excluded && is_dir_link && links_into(&path, target)) {
+                        ctx.emit(SearchResult { path: path.to_string_lossy().into_owned(), depth: args.depth_histogram.then_some(ctx.depth + 1), ..SearchResult::default() });
+                    }
+                    // Nothing below the largest depth is reported, so the walk stops there
+                    let deepest = if args.leaf_dirs || patterns.contains_file.is_some() { ctx.depth + 1 } else { ctx.depth + 2 };
+                    if patterns.depth_range.is_some_and(|(_, end)| end.is_some_and(|end| deepest > end)) {
+                        continue;
+                    }
+
+                    // A link back to a directory already being searched would be walked forever
+                    let link_target = if is_dir_link { fs::canonicalize(&path).ok() } else { None };
+                    if let Some(target) = &link_target {
+                        let loops = ctx.link_targets.contains(target) ||
+                            fs::canonicalize(dir).is_ok_and(|current| current.starts_with(target));
+                        if loops {
+                            if !ctx.warm_only {
+                                other_error_occurred = true;
+                                error_message.push_str(&format!("Skipping {}: it leads back to {}, which is already being searched\n",
+                                    path.display(), target.display()));
+                            }
+                            continue;
+                        }
+                    }
+
+                    let followed = link_target.is_some();
+                    ctx.link_targets.extend(link_target);
+                    ctx.depth += 1;
+                    let outer_pruned = std::mem::replace(&mut ctx.pruned, excluded);
+                    let (mut sub_perm_denied, sub_error, sub_err_msg) = search_files(&path, ctx, args, patterns);
+                    ctx.pruned = outer_pruned;
+                    ctx.depth -= 1;
+                    if followed {
+                        ctx.link_targets.pop();
+                    }
+                    permission_denied_dirs.append(&mut sub_perm_denied);
+                    other_error_occurred |= sub_error;
+                    if !sub_err_msg.is_empty() {
+                        error_message.push_str(&sub_err_msg);
+                        if !sub_err_msg.ends_with('\n') {
+                            error_message.push('\n');
+                        }
+                    }
+                } else if ctx.warm_only {
+                    let _ = path.symlink_metadata();
+                } else if dir_hit || args.leaf_dirs || patterns.contains_file.is_some() || !depth_in_range(ctx.depth + 1, patterns) {
+                    continue;
+                } else if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
+                    let depth = args.depth_histogram.then_some(ctx.depth + 1);
+                    if ctx.content_pool.is_some() {
+                        ctx.queue_file(path, depth, args.search_archives);
+                        continue;
+                    }
+                    let (results, errors) = check_file(&path, file_name, depth, args.search_archives, ctx.io_limit.as_deref(), args, patterns);
+                    for e in errors {
+                        other_error_occurred = true;
+                        error_message.push_str(&e);
+                        error_message.push('\n');
+                    }
+
+                    // Archives can't be combined with `--dir-hit`, so a match is the file itself
+                    if args.dir_hit && !results.is_empty() {
+                        dir_hit = true;
+                        ctx.emit(SearchResult { path: dir.to_string_lossy().into_owned(), depth: args.depth_histogram.then_some(ctx.depth), ..SearchResult::default() });
+                    } else {
+                        for result in results {
+                            ctx.emit(result);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::PermissionDenied {
+                    permission_denied_dirs.push(dir.to_string_lossy().into_owned());
+                } else {
+                    other_error_occurred = true;
+                    if args.verbose >= 2 {
+                        error_message.push_str(&format!("Error accessing entry in {}: {}\n", dir.display(), error_detail(&e, args)));
+                    } else {
+                        error_message.push_str(&format!("Error accessing entry: {}\n", e));
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(result) = own_result {
+        ctx.emit(result);
+    }
+    ctx.ignores.truncate(outer_ignores);
+
+    (permission_denied_dirs, other_error_occurred, error_message)
+}
+
+/// Checks whether a directory should be skipped instead of descended into.
+///
+/// # Parameters
+///
+/// * `dir_name` - The name of the directory (its last path component).
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+///
+/// # Returns
+///
+/// `true` if the directory is hidden without `--hidden-dirs`, is one of the `--ignore-vcs`
+/// directories, or matches an exclude pattern applied to directories; `false` otherwise.
+fn prune_dir(dir_name: &str, args: &Args, patterns: &Patterns) -> bool {
+    let hidden = dir_name.starts_with('.') && dir_name != "." && dir_name != "..";
+    (hidden && !args.include_hidden_dirs()) ||
+        (args.ignore_vcs && VCS_DIRS.contains(&dir_name)) ||
+        (args.exclude_type.contains(&ExcludeType::Dir) &&
+            patterns.exclude.iter().any(|re| re.is_match(dir_name)))
+}
+
+/// Matches an entry name against the name patterns (`-f`, `--name-regex`, `--name-glob`),
+/// or scores it with `--fuzzy`.
+///
+/// # Parameters
+///
+/// * `name` - The name of the entry (its last path component).
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+///
+/// # Returns
+///
+/// Whether the name matches (always, when there are no name patterns), and its fuzzy score.
+fn name_pattern_matches(name: &str, args: &Args, patterns: &Patterns) -> (bool, Option<u32>) {
+    let folded_name;
+    let candidate_name = if args.ascii_fold {
+        folded_name = ascii_fold(name);
+        folded_name.as_str()
+    } else {
+        name
+    };
+    if args.fuzzy {
+        let score = patterns.fuzzy.iter().filter_map(|pattern| fuzzy_score(pattern, candidate_name)).max();
+        (patterns.fuzzy.is_empty() || score.is_some(), score)
+    } else {
+        (patterns.name.is_empty() || patterns.name.iter().any(|re| re.is_match(candidate_name)), None)
+    }
+}
+
+/// Checks an entry name against the `--name` patterns, which no content match can make up for.
+///
+/// # Parameters
+///
+/// * `name` - The name of the entry (its last path component).
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+///
+/// # Returns
+///
+/// Whether the name matches one of the patterns (always, when there are none).
+fn required_name_matches(name: &str, args: &Args, patterns: &Patterns) -> bool {
+    if patterns.required_name.is_empty() {
+        return true;
+    }
+    let candidate_name = if args.ascii_fold { ascii_fold(name) } else { name.to_string() };
+    patterns.required_name.iter().any(|re| re.is_match(&candidate_name))
+}
+
+/// Checks that a file name is neither hidden (unless hidden files are included) nor excluded.
+///
+/// # Parameters
+///
+/// * `file_name` - The name of the file (its last path component).
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+///
+/// # Returns
+///
+/// Whether a file with this name can be reported at all.
+fn file_name_allowed(file_name: &str, args: &Args, patterns: &Patterns) -> bool {
+    (args.include_hidden_files() || !file_name.starts_with('.')) &&
+        !(args.exclude_type.contains(&ExcludeType::File) &&
+            patterns.exclude.iter().any(|re| re.is_match(file_name)))
+}
+
+/// Checks whether an entry is a symlink, or on Windows any other reparse point such as a
+/// directory junction, without following it.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the entry.
+///
+/// # Returns
+///
+/// `true` if the entry is a link, `false` otherwise or if it can't be read.
+fn is_link(path: &Path) -> bool {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return false;
+    };
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+
+        const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+        if metadata.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT != 0 {
+            return true;
+        }
+    }
+    metadata.file_type().is_symlink()
+}
+
+/// Checks whether a symlink resolves to `target` or to something inside it, for `--links-to`.
+///
+/// Every link in the chain is followed, so a link to a link to `target` counts too.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the symlink.
+/// * `target` - The canonical path the link has to lead to.
+///
+/// # Returns
+///
+/// `false` if the link is dangling or leads elsewhere, `true` otherwise.
+fn links_into(path: &Path, target: &Path) -> bool {
+    fs::canonicalize(path).is_ok_and(|resolved| resolved.starts_with(target))
+}
+
+/// Checks the depth of an entry below its search root against `--filter-by-depth-range`.
+///
+/// # Parameters
+///
+/// * `depth` - The depth of the entry, 1 for the entries directly in the root.
+/// * `patterns` - A reference to the compiled `Patterns`.
+///
+/// # Returns
+///
+/// Whether the depth is in the range (always, when there is none).
+fn depth_in_range(depth: usize, patterns: &Patterns) -> bool {
+    patterns.depth_range.is_none_or(|(start, end)| depth >= start && end.is_none_or(|end| depth <= end))
+}
+
+/// Checks whether a single file satisfies all search criteria.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file.
+/// * `file_name` - The file name component of `path`.
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+///
+/// # Returns
+///
+/// A tuple containing:
+/// * `Option<SearchResult>` - The result to report if the file matches, `None` otherwise.
+/// * `Option<String>` - An error message if the file content could not be read or rewritten.
+fn file_matches(path: &Path, file_name: &str, args: &Args, patterns: &Patterns) -> (Option<SearchResult>, Option<String>) {
+    let mut error = None;
+
+    // Symlinks stand for their target, unless symlinks themselves are asked for
+    let own_type = path.symlink_metadata().ok().and_then(|m| EntryType::of(m.file_type()));
+    let target_type = match own_type {
+        Some(EntryType::Symlink) => path.metadata().ok().and_then(|m| EntryType::of(m.file_type())),
+        other => other,
+    };
+    let special = target_type.is_some_and(EntryType::is_special);
+    let type_matches = if args.file_type.is_empty() {
+        !special
+    } else {
+        own_type.is_some_and(|t| args.file_type.contains(&t)) ||
+            (own_type == Some(EntryType::Symlink) && target_type.is_some_and(|t| args.file_type.contains(&t)))
+    };
+    if !type_matches {
+        return (None, None);
+    }
+
+    // A symlink is dangling when it can be read itself but its target can't
+    if args.broken_symlinks || args.valid_symlinks {
+        let is_symlink = own_type == Some(EntryType::Symlink);
+        let dangling = is_symlink && path.metadata().is_err();
+        if !is_symlink || dangling != args.broken_symlinks {
+            return (None, None);
+        }
+    }
+
+    if args.mine && !owned_by_current_user(path) {
+        return (None, None);
+    }
+
+    if patterns.links_to.as_deref().is_some_and(|target| own_type != Some(EntryType::Symlink) || !links_into(path, target)) {
+        return (None, None);
+    }
+
+    // Huge files are left out before anything reads them
+    if let Some(limit) = args.skip_larger_than {
+        if special || path.metadata().is_ok_and(|m| m.len() > limit) {
+            return (None, None);
+        }
+    }
+
+    // The start of the file is read at most once, by whichever check needs it first
+    let mut head = FileHead::new(path);
+
+    // Sniffing comes first so that files of another type are never read any further
+    if let Some(spec) = &args.mime {
+        match if special { Ok(None) } else { head.bytes().map(infer::get) } {
+            Ok(kind) if kind.is_some_and(|kind| mime_matches(spec, kind.mime_type())) => {}
+            Ok(_) => return (None, None),
+            Err(e) => return (None, Some(format!("Error reading file {}: {}", path.display(), error_detail(&e, args)))),
+        }
+    }
+
+    // Only the first line is read, however large the file
+    if let Some(re) = &patterns.shebang {
+        match if special { Ok(None) } else { head.bytes().map(shebang_line) } {
+            Ok(Some(line)) if re.is_match(&line) => {}
+            Ok(_) => return (None, None),
+            Err(e) => return (None, Some(format!("Error reading file {}: {}", path.display(), error_detail(&e, args)))),
+        }
+    }
+
+    // Like the #! line, the mark is found in the first bytes
+    if args.has_bom {
+        match if special { Ok(false) } else { head.bytes().map(starts_with_bom) } {
+            Ok(true) => {}
+            Ok(false) => return (None, None),
+            Err(e) => return (None, Some(format!("Error reading file {}: {}", path.display(), error_detail(&e, args)))),
+        }
+    }
+
+    let (pattern_matches, score) = name_pattern_matches(file_name, args, patterns);
+    let name_allowed = file_name_allowed(file_name, args, patterns) || patterns.force_included(path, false);
+    let name_matches = name_allowed && pattern_matches;
+
+    // The extension usually settles the language, so scripts are only read when it doesn't
+    if !patterns.languages.is_empty() {
+        match if special { Ok(false) } else { language_matches(path, &mut head, &patterns.languages) } {
+            Ok(true) => {}
+            Ok(false) => return (None, None),
+            Err(e) => return (None, Some(format!("Error reading file {}: {}", path.display(), error_detail(&e, args)))),
+        }
+    }
+
+    // Line limits are checked in the same pass as the content patterns
+    let count_total = args.min_lines.is_some() || args.max_lines.is_some() || args.min_match_ratio.is_some();
+    // Dotfiles can still be listed by name, but their contents are left alone, and so are
+    // archives whose members are searched instead
+    let search_contents = args.content && !(args.content_skip_hidden && file_name.starts_with('.')) &&
+        !(args.search_archives && is_zip_archive(path));
+    let (filter, content_not): (&[Regex], &[Regex]) = if search_contents {
+        (&patterns.filter, &patterns.content_not)
+    } else {
+        (&[], &[])
+    };
+
+    // Matching lines are counted for display, filtering or sorting
+    let counting = args.count_lines || args.min_matches.is_some() || args.min_match_ratio.is_some() || args.total_matches;
+    let count_all = counting || args.only_matching || args.format == OutputFormat::JsonlFull || args.export_matches.is_some();
+    // The HTML report shows the first matching line of each file
+    let capture = args.content && (args.format == OutputFormat::Html || patterns.template.as_ref()
+        .is_some_and(|parts| parts.iter().any(|part| matches!(part, TemplatePart::Capture(_)))));
+
+    // An empty file has no line for a positive pattern to match, so it isn't worth opening,
+    // unless only negated patterns are given (then it matches) or its lines are counted
+    let skip_empty = !args.include_zero_byte && !filter.is_empty() && !count_total &&
+        path.metadata().is_ok_and(|m| m.is_file() && m.len() == 0);
+    if skip_empty {
+        ZERO_BYTE_SKIPPED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let deadline = args.timeout.map(|timeout| Instant::now() + timeout);
+    // Reading a fifo or a device could block forever
+    let content = if (search_contents || count_total) && !special && !skip_empty {
+        match search_content(path, filter, content_not, &patterns.content_exclude, args.code_only, count_all, args.max_matches_per_file, count_total, capture, args.export_matches.is_some(), args.read_buffer_size, deadline,
+                             if args.only_matching { &patterns.spans } else { &[] }) {
+            Ok(content) if content.timed_out => {
+                error = Some(format!("Content search of {} timed out after {:?}", path.display(), args.timeout.unwrap_or_default()));
+                content
+            }
+            Ok(content) => content,
+            Err(e) => {
+                error = Some(format!("Error reading file {}: {}", path.display(), error_detail(&e, args)));
+                ContentMatch::default()
+            }
+        }
+    } else {
+        ContentMatch::default()
+    };
+    let content_matches = content.matched;
+
+    let path_text_matches = patterns.path_contains.as_deref().is_none_or(|text| {
+        let full_path = path.to_string_lossy();
+        if args.ignore_case {
+            full_path.to_lowercase().contains(text)
+        } else {
+            full_path.contains(text)
+        }
+    });
+
+    // Lengths are counted in characters, not bytes; `.`, `..` and the root are not path components
+    let name_length = file_name.chars().count();
+    let length_matches = args.name_longer_than.is_none_or(|n| name_length > n) &&
+        args.name_shorter_than.is_none_or(|n| name_length < n) &&
+        args.path_components.is_none_or(|n|
+            path.components().filter(|c| matches!(c, std::path::Component::Normal(_))).count() == n);
+
+    // Binary files have no line count and never match a line limit
+    let line_count_matches = !count_total || content.total_lines.is_some_and(|n|
+        args.min_lines.is_none_or(|min| n >= min) && args.max_lines.is_none_or(|max| n <= max));
+
+    let xattr_matches = args.xattr.as_deref().is_none_or(|spec| has_xattr(path, spec)) &&
+        (args.attr.is_empty() || has_attributes(path, &args.attr));
+
+    let perm_issues = if args.audit_perms { permission_issues(path) } else { Vec::new() };
+    let perms_match = !args.audit_perms || !perm_issues.is_empty();
+
+    // The modification time is converted to a local date, so the day runs from 00:00 inclusive
+    // to the next 00:00 exclusive in the timezone in effect at that instant (23 or 25 hours
+    // long when daylight saving time changes)
+    let day_matches = args.modified_on.is_none_or(|day| path.metadata().and_then(|m| m.modified())
+        .is_ok_and(|modified| chrono::DateTime::<chrono::Local>::from(modified).date_naive() == day));
+
+    // Access times are read last since they need an extra stat per candidate
+    let since_last_run_matches = patterns.modified_after.is_none_or(|last_run| path.metadata().and_then(|m| m.modified())
+        .is_ok_and(|modified| modified > last_run));
+    let time_matches = day_matches && since_last_run_matches && args.accessed_within.is_none_or(|window|
+        is_within(path.metadata().and_then(|m| m.accessed()), window)) &&
+        args.created_within.is_none_or(|window| is_within(created_time(path, args.created_fallback_mtime), window));
+
+    // Negated patterns make the file content the only deciding factor, and so does --filter
+    // once --name has taken over the file names
+    let by_name = required_name_matches(file_name, args, patterns);
+    let matches = by_name && if !patterns.content_not.is_empty() {
+        content_matches
+    } else if args.content && !patterns.required_name.is_empty() {
+        name_allowed && (patterns.filter.is_empty() || content_matches)
+    } else {
+        name_matches || content_matches
+    };
+
+    // When counting, only files with at least one (or `--min-matches`) matching line are worth listing,
+    // and with `--min-match-ratio` the lines must also make up enough of the file
+    let count_matches = (!args.count_lines || content.matching_lines > 0) &&
+        args.min_matches.is_none_or(|n| content.matching_lines >= n) &&
+        args.min_match_ratio.is_none_or(|ratio| match_ratio_reached(&content, ratio));
+
+    let matches = matches && path_text_matches && length_matches && line_count_matches && xattr_matches && perms_match && time_matches && count_matches;
+
+    let mut diff = None;
+    if let (true, Some((re, replacement))) = (matches && content_matches, &patterns.replace) {
+        if args.diff {
+            match replaced_content(path, re, replacement) {
+                Ok(change) => diff = change.map(|(old, new)| unified_diff(path, &old, &new)),
+                Err(e) => error = Some(format!("Error reading file {}: {}", path.display(), error_detail(&e, args))),
+            }
+        } else if let Err(e) = replace_in_file(path, re, replacement, args.backup) {
+            error = Some(format!("Error rewriting file {}: {}", path.display(), error_detail(&e, args)));
+        }
+    }
+
+    let result = matches.then(|| SearchResult {
+        path: path.to_string_lossy().into_owned(),
+        matching_lines: counting.then_some(content.matching_lines),
+        truncated: content.truncated,
+        captures: content.captures,
+        spans: content.spans,
+        first_line: content.line_numbers.first().copied(),
+        line_numbers: if args.format == OutputFormat::JsonlFull || args.export_matches.is_some() { content.line_numbers } else { Vec::new() },
+        matched_lines: content.lines,
+        score,
+        perm_issues,
+        diff,
+        size: args.top_largest.and_then(|_| path.metadata().ok()).map(|m| m.len()),
+        blame: None,
+        details: None,
+        depth: None,
+    });
+
+    (result, error)
+}
+
+/// Tells whether `--search-archives` looks into a file, from its `.zip` extension.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file.
+///
+/// # Returns
+///
+/// `true` if the extension is `zip`, ignoring case.
+fn is_zip_archive(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case("zip"))
+}
+
+/// Matches the members of a `.zip` archive, for `--search-archives`.
+///
+/// Archives with an encrypted member are skipped and reported with the errors, since their
+/// content can't be read without a password.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the archive.
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+///
+/// # Returns
+///
+/// A tuple containing the matching members, with paths such as `archive.zip::dir/file`,
+/// and the errors met while reading the archive.
+fn search_archive(path: &Path, args: &Args, patterns: &Patterns) -> (Vec<SearchResult>, Vec<String>) {
+    let read_error = |e: io::Error| format!("Error reading archive {}: {}", path.display(), error_detail(&e, args));
+    let mut archive = match File::open(path).and_then(|file| zip::ZipArchive::new(BufReader::new(file)).map_err(io::Error::from)) {
+        Ok(archive) => archive,
+        Err(e) => return (Vec::new(), vec![read_error(e)]),
+    };
+
+    for index in 0..archive.len() {
+        match archive.by_index_raw(index) {
+            Ok(member) if member.encrypted() => {
+                return (Vec::new(), vec![format!("Skipping encrypted archive {}: its members can't be read without a password", path.display())]);
+            }
+            Ok(_) => {}
+            Err(e) => return (Vec::new(), vec![read_error(e.into())]),
+        }
+    }
+
+    let mut results = Vec::new();
+    let mut errors = Vec::new();
+    for index in 0..archive.len() {
+        let member = match archive.by_index(index) {
+            Ok(member) => member,
+            Err(e) => {
+                errors.push(read_error(e.into()));
+                continue;
+            }
+        };
+        let name = match member.name() {
+            Ok(name) if !member.is_dir() => name.into_owned(),
+            _ => continue,
+        };
+        let member_path = format!("{}::{}", path.display(), name);
+        let (result, error) = archive_member_matches(&member_path, &name, &mut BufReader::new(member), args, patterns);
+        results.extend(result);
+        errors.extend(error);
+    }
+    (results, errors)
+}
+
+/// Checks whether an archive member satisfies the name and content criteria.
+///
+/// Criteria that need a file on disk, such as sizes, times, types or permissions, don't
+/// apply to archive members.
+///
+/// # Parameters
+///
+/// * `member_path` - The path the member is reported under, `archive.zip::dir/file`.
+/// * `name` - The path of the member inside the archive.
+/// * `reader` - The decompressed content of the member.
+/// * `args` - A reference to `Args` containing the search criteria and options.
+/// * `patterns` - A reference to the compiled `Patterns`.
+///
+/// # Returns
+///
+/// A tuple containing the result to report if the member matches, and an error message
+/// if its content could not be read.
+fn archive_member_matches(member_path: &str, name: &str, reader: &mut dyn BufRead, args: &Args, patterns: &Patterns) -> (Option<SearchResult>, Option<String>) {
+    let mut error = None;
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    let (pattern_matches, score) = name_pattern_matches(file_name, args, patterns);
+    let name_allowed = file_name_allowed(file_name, args, patterns);
+
+    let count_total = args.min_lines.is_some() || args.max_lines.is_some() || args.min_match_ratio.is_some();
+    let search_contents = args.content && !(args.content_skip_hidden && file_name.starts_with('.'));
+    let (filter, content_not): (&[Regex], &[Regex]) = if search_contents {
+        (&patterns.filter, &patterns.content_not)
+    } else {
+        (&[], &[])
+    };
+    let counting = args.count_lines || args.min_matches.is_some() || args.min_match_ratio.is_some() || args.total_matches;
+    let capture = args.content && (args.format == OutputFormat::Html || patterns.template.as_ref()
+        .is_some_and(|parts| parts.iter().any(|part| matches!(part, TemplatePart::Capture(_)))));
+    let comment_marker = if args.code_only { line_comment_marker(Path::new(file_name)) } else { None };
+    let deadline = args.timeout.map(|timeout| Instant::now() + timeout);
+
+    let content = if search_contents || count_total {
+        match search_lines(reader, filter, content_not, &patterns.content_exclude, comment_marker, counting || args.only_matching || args.format == OutputFormat::JsonlFull || args.export_matches.is_some(),
+                           args.max_matches_per_file, count_total, capture, args.export_matches.is_some(), deadline,
+                           if args.only_matching { &patterns.spans } else { &[] }) {
+            Ok(content) if content.timed_out => {
+                error = Some(format!("Content search of {} timed out after {:?}", member_path, args.timeout.unwrap_or_default()));
+                content
+            }
+            Ok(content) => content,
+            Err(e) => {
+                error = Some(format!("Error reading file {}: {}", member_path, error_detail(&e, args)));
+                ContentMatch::default()
+            }
+        }
+    } else {
+        ContentMatch::default()
+    };
+
+    // Decided as in `file_matches`
+    let by_name = required_name_matches(file_name, args, patterns);
+    let matches = by_name && if !patterns.content_not.is_empty() {
+        content.matched
+    } else if args.content && !patterns.required_name.is_empty() {
+        name_allowed && (patterns.filter.is_empty() || content.matched)
+    } else {
+        (name_allowed && pattern_matches) || content.matched
+    };
+    let path_text_matches = patterns.path_contains.as_deref().is_none_or(|text| if args.ignore_case {
+        member_path.to_lowercase().contains(text)
+    } else {
+        member_path.contains(text)
+    });
+    let line_count_matches = !count_total || content.total_lines.is_some_and(|n|
+        args.min_lines.is_none_or(|min| n >= min) && args.max_lines.is_none_or(|max| n <= max));
+    let count_matches = (!args.count_lines || content.matching_lines > 0) &&
+        args.min_matches.is_none_or(|n| content.matching_lines >= n) &&
+        args.min_match_ratio.is_none_or(|ratio| match_ratio_reached(&content, ratio));
+
+    let result = (matches && path_text_matches && line_count_matches && count_matches).then(|| SearchResult {
+        path: member_path.to_string(),
+        matching_lines: counting.then_some(content.matching_lines),
+        truncated: content.truncated,
+        captures: content.captures,
+        spans: content.spans,
+        first_line: content.line_numbers.first().copied(),
+        line_numbers: if args.format == OutputFormat::JsonlFull || args.export_matches.is_some() { content.line_numbers } else { Vec::new() },
+        matched_lines: content.lines,
+        score,
+        ..SearchResult::default()
+    });
+    (result, error)
+}
+
+/// Checks a detected MIME type against the one given to `--mime`.
+///
+/// # Parameters
+///
+/// * `spec` - The MIME type to look for, where `type/*` matches any subtype.
+/// * `mime` - The MIME type detected from the file content.
+///
+/// # Returns
+///
+/// `true` if the types are equal, ignoring case, or `mime` falls under a `type/*` spec.
+fn mime_matches(spec: &str, mime: &str) -> bool {
+    match spec.strip_suffix("/*") {
+        Some(top_level) => mime.split('/').next().is_some_and(|t| t.eq_ignore_ascii_case(top_level)),
+        None => spec.eq_ignore_ascii_case(mime),
+    }
+}
+
+/// The first bytes of a file, read on first use and shared by the checks that sniff it.
+struct FileHead<'a> {
+    path: &'a Path,
+    bytes: Option<Vec<u8>>,
+}
+
+impl<'a> FileHead<'a> {
+    fn new(path: &'a Path) -> Self {
+        FileHead { path, bytes: None }
+    }
+
+    /// Returns the first `SNIFF_LEN` bytes of the file, reading them the first time.
+    fn bytes(&mut self) -> io::Result<&[u8]> {
+        if self.bytes.is_none() {
+            let mut bytes = Vec::new();
+            File::open(self.path)?.take(SNIFF_LEN).read_to_end(&mut bytes)?;
+            self.bytes = Some(bytes);
+        }
+        Ok(self.bytes.as_deref().unwrap_or_default())
+    }
+}
+
+/// Extracts the `#!` line a script starts with.
+///
+/// # Parameters
+///
+/// * `head` - The first bytes of the file.
+///
+/// # Returns
+///
+/// The first line without its line ending, or `None` if the file is empty, doesn't start
+/// with `#!`, or its first line isn't valid UTF-8 text.
+fn shebang_line(head: &[u8]) -> Option<String> {
+    let head = &head[..head.len().min(SHEBANG_MAX_LEN as usize)];
+    let line = match head.iter().position(|&b| b == b'\n') {
+        Some(end) => &head[..=end],
+        None => head,
+    };
+    if !line.starts_with(b"#!") || line.contains(&0) {
+        return None;
+    }
+    String::from_utf8(line.to_vec()).ok().map(|line| line.trim_end_matches('\n').trim_end_matches('\r').to_string())
+}
+
+/// Checks whether a text file starts with a UTF-8 or UTF-16 byte-order mark, for `--has-bom`.
+///
+/// Binary files that happen to start with the same bytes are told apart by the text after
+/// the mark: a NUL character, or the signature of a known binary format, rules the file out.
+///
+/// # Parameters
+///
+/// * `head` - The first bytes of the file.
+///
+/// # Returns
+///
+/// Whether the file starts with a byte-order mark.
+fn starts_with_bom(head: &[u8]) -> bool {
+    let head = &head[..head.len().min(BOM_SNIFF_LEN as usize)];
+    let (text, unit) = if let Some(text) = head.strip_prefix(b"\xEF\xBB\xBF") {
+        (text, 1)
+    } else if let Some(text) = head.strip_prefix(b"\xFF\xFE").or_else(|| head.strip_prefix(b"\xFE\xFF")) {
+        (text, 2)
+    } else {
+        return false;
+    };
+    let has_nul = text.chunks_exact(unit).any(|c| c.iter().all(|&b| b == 0));
+    !has_nul && infer::get(head).is_none()
+}
+
+/// Checks whether a file is written in one of the `--lang` languages.
+///
+/// A known extension decides; otherwise the interpreter of the `#!` line is looked up, after
+/// `env` and its options and without a version suffix, so `#!/usr/bin/env python3` is Python.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file.
+/// * `head` - The start of the file, only read when the extension doesn't settle the language.
+/// * `languages` - The languages to look for.
+///
+/// # Returns
+///
+/// An `io::Result` telling whether the file is in one of the languages.
+fn language_matches(path: &Path, head: &mut FileHead, languages: &[&Language]) -> io::Result<bool> {
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        if let Some(language) = LANGUAGES.iter().find(|language| language.extensions.iter().any(|e| e.eq_ignore_ascii_case(extension))) {
+            return Ok(languages.iter().any(|l| l.name == language.name));
+        }
+    }
+
+    let Some(line) = shebang_line(head.bytes()?) else {
+        return Ok(false);
+    };
+    let mut words = line[2..].split_whitespace()
+        .map(|word| word.rsplit('/').next().unwrap_or(word));
+    let mut interpreter = words.next();
+    if interpreter == Some("env") {
+        interpreter = words.find(|word| !word.starts_with('-') && !word.contains('='));
+    }
+    let Some(interpreter) = interpreter.map(|name| name.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.')) else {
+        return Ok(false);
+    };
+    Ok(languages.iter().any(|language| language.interpreters.contains(&interpreter)))
+}
+
+/// Computes the content of a text file with every match of `re` replaced, line by line.
+///
+/// Files containing NUL bytes or invalid UTF-8 are refused.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file.
+/// * `re` - The `Regex` whose matches are replaced.
+/// * `replacement` - The replacement text; `$1`, `${name}` refer to capture groups.
+///
+/// # Returns
+///
+/// An `io::Result` containing the original and the new content, or `None` if nothing matched.
+fn replaced_content(path: &Path, re: &Regex, replacement: &str) -> io::Result<Option<(String, String)>> {
+    let bytes = fs::read(path)?;
+    if bytes.contains(&0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "refusing to rewrite a binary file"));
+    }
+    let content = String::from_utf8(bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "refusing to rewrite a file that is not valid UTF-8"))?;
+
+    let mut changed = false;
+    let mut rewritten = String::with_capacity(content.len());
+    for line in content.split_inclusive('\n') {
+        let ending_len = if line.ends_with("\r\n") { 2 } else if line.ends_with('\n') { 1 } else { 0 };
+        let (text, ending) = line.split_at(line.len() - ending_len);
+        let replaced = re.replace_all(text, replacement);
+        changed |= replaced != text;
+        rewritten.push_str(&replaced);
+        rewritten.push_str(ending);
+    }
+
+    Ok(changed.then_some((content, rewritten)))
+}
+
+/// Replaces every match of `re` in a text file, line by line, and writes it back atomically.
+///
+/// The new content is written to a temporary file next to the original, which is then
+/// renamed over it. Files containing NUL bytes or invalid UTF-8 are refused.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file to rewrite.
+/// * `re` - The `Regex` whose matches are replaced.
+/// * `replacement` - The replacement text; `$1`, `${name}` refer to capture groups.
+/// * `backup` - If `true`, the original file is copied to `<name>.bak` first.
+///
+/// # Returns
+///
+/// An `io::Result` containing `true` if the file was changed, `false` if nothing matched.
+fn replace_in_file(path: &Path, re: &Regex, replacement: &str, backup: bool) -> io::Result<bool> {
+    let Some((_, rewritten)) = replaced_content(path, re, replacement)? else {
+        return Ok(false);
+    };
+
+    let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    if backup {
+        fs::copy(path, path.with_file_name(format!("{}.bak", file_name)))?;
+    }
+
+    let temp_path = path.with_file_name(format!(".{}.find_file.tmp", file_name));
+    let result = fs::write(&temp_path, rewritten)
+        .and_then(|_| fs::set_permissions(&temp_path, fs::metadata(path)?.permissions()))
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.map(|_| true)
+}
+
+/// Renders the changes made to a file as a unified diff with three lines of context.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file, named in the `---` and `+++` headers.
+/// * `old` - The current content.
+/// * `new` - The content after the replacement.
+///
+/// # Returns
+///
+/// The diff, ending with a newline.
+fn unified_diff(path: &Path, old: &str, new: &str) -> String {
+    let name = path.to_string_lossy();
+    similar::TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("a/{}", name.trim_start_matches("./")), &format!("b/{}", name.trim_start_matches("./")))
+        .to_string()
+}
+
+/// Prints a unified diff, coloring removed lines red, added lines green and hunk headers cyan.
+///
+/// # Parameters
+///
+/// * `out` - The writer the diff is printed to.
+/// * `diff` - The diff from `unified_diff`.
+///
+/// # Returns
+///
+/// An `io::Result` reporting whether the diff could be written.
+fn write_diff(out: &mut dyn Write, diff: &str) -> io::Result<()> {
+    for line in diff.lines() {
+        let colored_line = if line.starts_with("---") || line.starts_with("+++") {
+            line.bold()
+        } else if line.starts_with("@@") {
+            line.cyan()
+        } else if line.starts_with('-') {
+            line.red()
+        } else if line.starts_with('+') {
+            line.green()
+        } else {
+            line.normal()
+        };
+        writeln!(out, "{}", colored_line)?;
+    }
+    Ok(())
+}
+
+/// Checks whether a file carries an extended attribute, given as `NAME` or `NAME=VALUE`.
+///
+/// Attributes that can't be read are treated as missing.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file.
+/// * `spec` - The attribute name, optionally followed by `=` and the expected value.
+///
+/// # Returns
+///
+/// `true` if the attribute is present (with the expected value, if given), `false` otherwise.
+#[cfg(unix)]
+fn has_xattr(path: &Path, spec: &str) -> bool {
+    let (name, expected) = match spec.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (spec, None),
+    };
+    match xattr::get(path, name) {
+        Ok(Some(value)) => expected.is_none_or(|expected| value == expected.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Checks whether a file carries every one of the given Windows attributes, following symlinks.
+///
+/// Files whose metadata can't be read have no attributes.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file.
+/// * `attributes` - The attributes given to `--attr`.
+///
+/// # Returns
+///
+/// `true` if all the attribute bits are set, `false` otherwise.
+#[cfg(windows)]
+fn has_attributes(path: &Path, attributes: &[FileAttribute]) -> bool {
+    use std::os::windows::fs::MetadataExt;
+
+    path.metadata().is_ok_and(|m| attributes.iter().all(|attribute| m.file_attributes() & attribute.bit() != 0))
+}
+
+/// Reads the ID of the device holding a file, following symlinks.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file.
+///
+/// # Returns
+///
+/// The `st_dev` of the file, or `None` if its metadata can't be read.
+#[cfg(unix)]
+fn device_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    path.metadata().ok().map(|m| m.dev())
+}
+
+/// A mounted filesystem, from a line of `/proc/self/mountinfo`.
+#[derive(Debug)]
+struct Mount {
+    mount_point: PathBuf,
+    fs_type: String,
+}
+
+/// Mount table read the first time `--fs-type` needs it.
+static MOUNTS: std::sync::OnceLock<Vec<Mount>> = std::sync::OnceLock::new();
+
+/// Parses the content of `/proc/self/mountinfo`.
+///
+/// Each line reads `ID PARENT MAJOR:MINOR ROOT MOUNT_POINT OPTIONS [OPTIONAL...] - TYPE SOURCE
+/// SUPER_OPTIONS`: the mount point is the fifth field, with spaces, tabs, newlines and
+/// backslashes escaped as `\040`, `\011`, `\012` and `\134`, and since the number of
+/// optional fields varies, the type is the first field after the lone `-`. Malformed lines
+/// are skipped.
+///
+/// # Parameters
+///
+/// * `content` - The content of the file.
+///
+/// # Returns
+///
+/// The mounts, in the order of the file (a later mount hides an earlier one on the same point).
+fn parse_mountinfo(content: &str) -> Vec<Mount> {
+    let unescape = |field: &str| {
+        let mut text = String::new();
+        let mut rest = field;
+        while let Some(i) = rest.find('\\') {
+            text.push_str(&rest[..i]);
+            match rest.get(i + 1..i + 4).and_then(|code| u8::from_str_radix(code, 8).ok()) {
+                Some(byte) => {
+                    text.push(char::from(byte));
+                    rest = &rest[i + 4..];
+                }
+                None => {
+                    text.push('\\');
+                    rest = &rest[i + 1..];
+                }
+            }
+        }
+        text.push_str(rest);
+        text
+    };
+
+    content.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let separator = fields.iter().position(|field| *field == "-")?;
+        let mount_point = fields.get(4).filter(|_| separator > 5)?;
+        Some(Mount { mount_point: PathBuf::from(unescape(mount_point)), fs_type: fields.get(separator + 1)?.to_string() })
+    }).collect()
+}
+
+/// Finds the type of filesystem a directory lies on, from the mount with the longest
+/// mount point containing its resolved path.
+///
+/// # Parameters
+///
+/// * `dir` - A reference to the `Path` of the directory.
+///
+/// # Returns
+///
+/// The filesystem type, e.g. `ext4`, or `None` if the directory or the mount table can't be read.
+fn filesystem_type(dir: &Path) -> Option<&'static str> {
+    let mounts = MOUNTS.get_or_init(|| fs::read_to_string("/proc/self/mountinfo")
+        .map(|content| parse_mountinfo(&content))
+        .unwrap_or_default());
+    let resolved = fs::canonicalize(dir).ok()?;
+    mounts.iter()
+        .filter(|mount| resolved.starts_with(&mount.mount_point))
+        // The last of the longest mount points is the one on top
+        .max_by_key(|mount| mount.mount_point.components().count())
+        .map(|mount| mount.fs_type.as_str())
+}
+
+/// Device IDs are Unix-only, so `--same-filesystem` never stops the walk (a warning is printed in `main`).
+#[cfg(not(unix))]
+fn device_id(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// File attributes only exist on Windows, so the filter is a no-op (a warning is printed in `main`).
+#[cfg(not(windows))]
+fn has_attributes(_path: &Path, _attributes: &[FileAttribute]) -> bool {
+    true
+}
+
+/// Extended attributes are not supported here, so the filter is a no-op (a warning is printed in `main`).
+#[cfg(not(unix))]
+fn has_xattr(_path: &Path, _spec: &str) -> bool {
+    true
+}
+
+/// Lists the unsafe permissions of a file, following symlinks.
+///
+/// Files whose metadata can't be read have no issues.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file.
+///
+/// # Returns
+///
+/// The `PermIssue`s found, in `PermIssue::ALL` order.
+#[cfg(unix)]
+fn permission_issues(path: &Path) -> Vec<PermIssue> {
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(mode) = path.metadata().map(|m| m.mode()) else {
+        return Vec::new();
+    };
+    let bits = [(PermIssue::WorldWritable, 0o002), (PermIssue::Setuid, 0o4000), (PermIssue::Setgid, 0o2000)];
+    bits.into_iter().filter(|(_, bit)| mode & bit != 0).map(|(issue, _)| issue).collect()
+}
+
+/// Checks whether a file belongs to the effective user of this process, following symlinks.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file.
+///
+/// # Returns
+///
+/// `true` if the owner of the file is the effective UID, `false` otherwise or if its
+/// metadata can't be read.
+#[cfg(unix)]
+fn owned_by_current_user(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let euid = unsafe { libc::geteuid() };
+    path.metadata().is_ok_and(|m| m.uid() == euid)
+}
+
+/// File owners are Unix-only; `main` refuses `--mine` elsewhere.
+#[cfg(not(unix))]
+fn owned_by_current_user(_path: &Path) -> bool {
+    false
+}
+
+/// Permission bits are Unix-only; `main` refuses `--audit-perms` elsewhere.
+#[cfg(not(unix))]
+fn permission_issues(_path: &Path) -> Vec<PermIssue> {
+    Vec::new()
+}
+
+/// Reads the creation time of a file, optionally falling back to its modification time.
+///
+/// Many Linux filesystems (or older kernels) don't expose creation times. The first time
+/// this happens a warning is printed, and without `fallback_mtime` the file doesn't match.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the file.
+/// * `fallback_mtime` - If `true`, the modification time is used when the creation time is unavailable.
+///
+/// # Returns
+///
+/// An `io::Result` containing the timestamp to compare.
+fn created_time(path: &Path, fallback_mtime: bool) -> io::Result<SystemTime> {
+    let metadata = path.metadata()?;
+    match metadata.created() {
+        Ok(created) => Ok(created),
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            CREATED_UNSUPPORTED.call_once(|| {
+                let detail = if fallback_mtime {
+                    "; using modification times instead"
+                } else {
+                    "; such files are not matched (use --created-fallback-mtime to compare modification times)"
+                };
+                eprintln!("{}", format!("Warning: creation time is not available on this filesystem{}", detail).yellow());
+            });
+            if fallback_mtime { metadata.modified() } else { Err(e) }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Tells whether the matching lines make up at least `ratio` of a file, for `--min-match-ratio`.
+///
+/// Empty files and files without a line count (binary ones) never do.
+///
+/// # Parameters
+///
+/// * `content` - A reference to the `ContentMatch` of the file, searched with every line counted.
+/// * `ratio` - The least fraction of matching lines, from 0 to 1.
+///
+/// # Returns
+///
+/// `true` if the file is relevant enough to be reported, `false` otherwise.
+fn match_ratio_reached(content: &ContentMatch, ratio: f64) -> bool {
+    content.total_lines.is_some_and(|total| total > 0 && content.matching_lines as f64 >= ratio * total as f64)
+}
+
+/// Searches for content within a file based on given regex patterns.
+///
+/// # Parameters
+///
+/// * `file_path` - A reference to a `Path` representing the file to search in.
+/// * `filter_regexes` - A slice of `Regex` patterns to match against file content.
+/// * `content_not_regexes` - A slice of `Regex` patterns that must not match any line.
+/// * `exclude_line_regexes` - Lines matching one of these are skipped by the other patterns,
+///   though they still count towards the total.
+/// * `code_only` - If `true`, line comments are stripped before matching in files with a known extension.
+/// * `count_all` - If `true`, every line is scanned so that all matching lines are counted.
+/// * `max_matches` - Stop counting after this many matching lines, if set.
+/// * `count_total` - If `true`, the whole file is read to count its lines. Files containing
+///   NUL bytes or invalid UTF-8 are treated as binary and get no line count.
+/// * `capture` - If `true`, the capture groups of the first matching line are returned.
+/// * `keep_lines` - If `true`, the text of the counted matching lines is returned with their numbers.
+/// * `buffer_size` - Capacity of the read buffer, or `None` for the `BufReader` default.
+/// * `deadline` - Stop reading once this instant has passed, reporting the search as timed out.
+/// * `span_regexes` - Patterns whose every match on a matching line is collected; pass
+///   `count_all` as well so that the whole file is read.
+///
+/// # Returns
+///
+/// A `Result` containing:
+/// * `Ok(ContentMatch)` - Whether any regex pattern matches the file content (or there are none)
+///   while no negated pattern does, how many lines matched, and the total number of lines.
+/// * `Err(io::Error)` - If there was an error reading the file.
+///
+/// # Example
+///
+/// ```
+/// let filter_regexes = vec![Regex::new(r"important").unwrap()];
+/// match search_content(Path::new("/path/to/file.txt"), &filter_regexes, &[], &[], false, false, None, false, false, false, None, None, &[]) {
+///     Ok(content) if content.matched => println!("Content found"),
+///     Ok(_) => println!("Content not found"),
+///     Err(e) => eprintln!("Error searching file: {}", e),
+/// }
+/// ```
+#[allow(clippy::too_many_arguments)]
+fn search_content(file_path: &Path, filter_regexes: &[Regex], content_not_regexes: &[Regex], exclude_line_regexes: &[Regex], code_only: bool, count_all: bool, max_matches: Option<usize>, count_total: bool, capture: bool, keep_lines: bool, buffer_size: Option<usize>, deadline: Option<Instant>, span_regexes: &[Regex]) -> io::Result<ContentMatch> {
+    let file = File::open(file_path)?;
+    let mut reader = match buffer_size {
+        Some(capacity) => BufReader::with_capacity(capacity, file),
+        None => BufReader::new(file),
+    };
+    let comment_marker = if code_only { line_comment_marker(file_path) } else { None };
+    search_lines(&mut reader, filter_regexes, content_not_regexes, exclude_line_regexes, comment_marker, count_all, max_matches, count_total, capture, keep_lines, deadline, span_regexes)
+}
+
+/// Matches the lines read from `reader` against content patterns, like `search_content`
+/// does for a file.
+///
+/// # Parameters
+///
+/// * `reader` - Where the lines are read from, such as a file or an archive member.
+/// * `comment_marker` - Line comment marker after which a line is ignored, with `--code-only`.
+///
+/// The other parameters and the returned value are those of `search_content`.
+#[allow(clippy::too_many_arguments)]
+fn search_lines(reader: &mut dyn BufRead, filter_regexes: &[Regex], content_not_regexes: &[Regex], exclude_line_regexes: &[Regex], comment_marker: Option<&str>, count_all: bool, max_matches: Option<usize>, count_total: bool, capture: bool, keep_lines: bool, deadline: Option<Instant>, span_regexes: &[Regex]) -> io::Result<ContentMatch> {
+    // Only negated patterns given: any file that lacks them matches
+    let mut positive_found = filter_regexes.is_empty() && !content_not_regexes.is_empty();
+    let mut matching_lines = 0;
+    let mut truncated = false;
+    let mut total_lines = 0;
+    let mut captures = Vec::new();
+    let mut spans = Vec::new();
+    let mut line_numbers = Vec::new();
+    let mut lines = Vec::new();
+    let mut buf = Vec::new();
+
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        let line = match std::str::from_utf8(&buf) {
+            Ok(line) if !(count_total && line.contains('\0')) => line.trim_end_matches('\n').trim_end_matches('\r'),
+            // Binary files get no line count; when none is needed, invalid UTF-8 is an error
+            _ if count_total => return Ok(ContentMatch::default()),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8")),
+        };
+        total_lines += 1;
+        // Checking the clock on every line would cost more than most matches
+        if total_lines % DEADLINE_CHECK_INTERVAL == 0 && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(ContentMatch { timed_out: true, ..ContentMatch::default() });
+        }
+        if exclude_line_regexes.iter().any(|re| re.is_match(line)) {
+            continue;
+        }
+        let code = match comment_marker {
+            Some(marker) => line.split(marker).next().unwrap_or(""),
+            None => line,
+        };
+        let found = !truncated && filter_regexes.iter().any(|re| re.is_match(code));
+        if content_not_regexes.iter().any(|re| re.is_match(code)) {
+            return Ok(ContentMatch::default());
+        }
+        if found && capture && !positive_found {
+            captures = filter_regexes.iter()
+                .find_map(|re| re.captures(code))
+                .map(|caps| caps.iter().map(|group| group.map(|m| m.as_str().to_string())).collect())
+                .unwrap_or_default();
+        }
+        if found {
+            for re in span_regexes {
+                spans.extend(re.captures_iter(code)
+                    .map(|caps| caps.iter().map(|group| group.map(|m| m.as_str().to_string())).collect()));
+            }
+        }
+        positive_found |= found;
+
+        // A match beyond the maximum only marks the count as truncated
+        if found && max_matches.is_some_and(|max| matching_lines >= max) {
+            truncated = true;
+        } else if found {
+            matching_lines += 1;
+            line_numbers.push(total_lines);
+            if keep_lines {
+                lines.push(line.to_string());
+            }
+        }
+
+        // Without negated patterns there is nothing left to rule the file out
+        let done = (!count_all || truncated) && !count_total;
+        if positive_found && content_not_regexes.is_empty() && done {
+            break;
+        }
+    }
+
+    Ok(ContentMatch { matched: positive_found, matching_lines, truncated, total_lines: count_total.then_some(total_lines), captures, spans, timed_out: false, line_numbers, lines })
+}
+
+/// Returns the line comment marker used by a source file, based on its extension.
+///
+/// Only `//` and `#` line comments are known; block comments and comment markers
+/// inside string literals are not recognized.
+///
+/// # Parameters
+///
+/// * `file_path` - A reference to the `Path` of the file.
+///
+/// # Returns
+///
+/// The marker starting a line comment, or `None` if the extension is not recognized.
+fn line_comment_marker(file_path: &Path) -> Option<&'static str> {
+    let extension = file_path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "rs" | "c" | "h" | "cc" | "cpp" | "hpp" | "cs" | "java" | "kt" | "go" | "js" | "jsx" | "ts" | "tsx" | "swift" | "scala" | "dart" => Some("//"),
+        "py" | "sh" | "bash" | "zsh" | "rb" | "pl" | "r" | "toml" | "yaml" | "yml" | "cfg" | "conf" | "ini" | "mk" => Some("#"),
+        _ => None,
+    }
+}
+
+/// Prints a matching file as a single JSON Lines record.
+///
+/// Size and modification time (seconds since the Unix epoch) are included
+/// when the file's metadata can be read, and omitted otherwise. Results carrying
+/// `FileDetails` get them too, along with their matching line numbers.
+///
+/// # Parameters
+///
+/// * `out` - The writer the record is printed to.
+/// * `path` - A reference to the `Path` of the matching file.
+/// * `result` - The `SearchResult` describing the match.
+///
+/// # Returns
+///
+/// An `io::Result` reporting whether the record could be written.
+fn emit_json_line(out: &mut dyn Write, path: &Path, result: &SearchResult) -> io::Result<()> {
+    let record = json_record(path, result);
+    let line = match &result.details {
+        Some(details) => serde_json::to_string(&FullJsonRecord { record, details, line_numbers: &result.line_numbers }),
+        None => serde_json::to_string(&record),
+    }.map_err(io::Error::other)?;
+    writeln!(out, "{}", line)
+}
+
+/// Gathers the metadata `--format jsonl-full` adds to each record.
+///
+/// The type is the one of the entry itself, while permissions and owner are read through
+/// symlinks, like the size, unless the link is dangling.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the match.
+///
+/// # Returns
+///
+/// The `FileDetails`, with the fields that could not be read left empty.
+fn file_details(path: &Path) -> FileDetails {
+    let file_type = path.symlink_metadata().ok().map(|m| match EntryType::of(m.file_type()) {
+        _ if m.is_dir() => "dir",
+        Some(EntryType::File) => "file",
+        Some(EntryType::Symlink) => "symlink",
+        Some(EntryType::Socket) => "socket",
+        Some(EntryType::Fifo) => "fifo",
+        Some(EntryType::BlockDevice) => "block",
+        Some(EntryType::CharDevice) => "char",
+        None => "unknown",
+    });
+    let Ok(metadata) = path.metadata().or_else(|_| path.symlink_metadata()) else {
+        return FileDetails { file_type, ..FileDetails::default() };
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        FileDetails {
+            file_type,
+            permissions: Some(format!("{:04o}", metadata.mode() & 0o7777)),
+            readonly: Some(metadata.permissions().readonly()),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            owner: user_name(metadata.uid()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        FileDetails { file_type, readonly: Some(metadata.permissions().readonly()), ..FileDetails::default() }
+    }
+}
+
+/// Looks up the name of a user in the password database.
+///
+/// # Parameters
+///
+/// * `uid` - The user ID.
+///
+/// # Returns
+///
+/// The login name, or `None` if there is no such user.
+#[cfg(unix)]
+fn user_name(uid: u32) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 4096];
+    // SAFETY: an all-zero passwd is a valid value for getpwuid_r to overwrite
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the duration of the call, and `buf.len()` is its size
+    let status = unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if status != 0 || found.is_null() {
+        return None;
+    }
+    // SAFETY: on success, pw_name points to a NUL-terminated string inside `buf`
+    Some(unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) }.to_string_lossy().into_owned())
+}
+
+/// Builds the JSON record of a matching file.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the matching file, used to read its metadata.
+/// * `result` - The `SearchResult` describing the match.
+///
+/// # Returns
+///
+/// A `JsonRecord` borrowing the path from `result`.
+fn json_record<'a>(path: &Path, result: &'a SearchResult) -> JsonRecord<'a> {
+    let metadata = path.metadata().ok();
+    JsonRecord {
+        path: &result.path,
+        size: metadata.as_ref().map(|m| m.len()),
+        modified: metadata.as_ref()
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs()),
+        matching_lines: result.matching_lines,
+        truncated: result.truncated,
+        matches: result.spans.iter().filter_map(|span| span.first()?.as_deref()).collect(),
+        blame: result.blame.as_ref(),
+    }
+}
+
+/// Formats a single result for the text output.
+///
+/// # Parameters
+///
+/// * `args` - A reference to `Args` containing the output options.
+/// * `result` - The `SearchResult` to format.
+///
+/// # Returns
+///
+/// The path, followed by `:N` with the number of matching lines under `--count-lines`,
+/// `(truncated)` when reading stopped at `--max-matches-per-file`, and the author and
+/// commit found by `--blame`.
+fn format_result(args: &Args, result: &SearchResult) -> String {
+    let mut formatted = match result.matching_lines {
+        Some(count) if args.count_lines => format!("{}:{}", result.path, count),
+        _ => result.path.clone(),
+    };
+    if result.truncated {
+        formatted.push_str(" (truncated)");
+    }
+    if let Some(blame) = &result.blame {
+        formatted.push_str(&format!(" [{}, {}]", blame.author, blame.commit));
+    }
+    formatted
+}
+
+/// Renders a match with the parsed `--format-template`.
+///
+/// Capture groups that did not participate in the match, or that do not exist,
+/// are rendered as empty text.
+///
+/// # Parameters
+///
+/// * `parts` - The parts of the template, from `parse_template`.
+/// * `path` - The path of the match.
+/// * `captures` - The capture groups `{N}` refers to.
+///
+/// # Returns
+///
+/// The rendered line.
+fn render_template(parts: &[TemplatePart], path: &str, captures: &[Option<String>]) -> String {
+    parts.iter().map(|part| match part {
+        TemplatePart::Literal(text) => text.as_str(),
+        TemplatePart::Path => path,
+        TemplatePart::Name => Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or(""),
+        TemplatePart::Capture(group) => captures.get(*group).and_then(|c| c.as_deref()).unwrap_or(""),
+    }).collect()
+}
+
+/// Prints the matched parts of a file's content, one per line, for `--only-matching`.
+///
+/// # Parameters
+///
+/// * `out` - The writer the matches are printed to.
+/// * `patterns` - A reference to the compiled `Patterns`, holding the `--format-template`.
+/// * `result` - The `SearchResult` holding the matches.
+///
+/// # Returns
+///
+/// An `io::Result` reporting whether the matches could be written.
+fn write_spans(out: &mut dyn Write, patterns: &Patterns, result: &SearchResult) -> io::Result<()> {
+    for span in &result.spans {
+        match &patterns.template {
+            Some(parts) => writeln!(out, "{}", render_template(parts, &result.path, span))?,
+            None => writeln!(out, "{}", span.first().and_then(|m| m.as_deref()).unwrap_or(""))?,
+        }
+    }
+    Ok(())
+}
+
+/// Counts the matching files per extension, for `--by-ext`.
+///
+/// Extensions are compared case-insensitively and shown with a leading dot; files
+/// without one are counted under `(none)`.
+///
+/// # Parameters
+///
+/// * `files` - A slice of the matching files.
+///
+/// # Returns
+///
+/// The extensions and their counts, most frequent first, ties sorted by extension.
+fn extension_histogram(files: &[SearchResult]) -> Vec<(String, usize)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for file in files {
+        *counts.entry(extension_key(&file.path)).or_default() += 1;
+    }
+
+    let mut histogram: Vec<(String, usize)> = counts.into_iter().collect();
+    histogram.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    histogram
+}
+
+/// Counts the matching files per age bucket, for `--age-histogram`.
+///
+/// The age is the time since the last modification; files modified in the future count
+/// as less than a day old, and files whose modification time can't be read as `unknown`.
+///
+/// # Parameters
+///
+/// * `files` - A slice of the matching files.
+/// * `now` - The time the ages are measured from.
+///
+/// # Returns
+///
+/// Every bucket with its count, youngest first, followed by `unknown` if any file is in it.
+fn age_histogram(files: &[SearchResult], now: SystemTime) -> Vec<(&'static str, usize)> {
+    const DAY: u64 = 24 * 60 * 60;
+    let mut histogram = vec![("< 1 day", 0), ("1-7 days", 0), ("7-30 days", 0), ("> 30 days", 0), ("unknown", 0)];
+    for file in files {
+        let bucket = match fs::metadata(&file.path).and_then(|m| m.modified()) {
+            Ok(modified) => match now.duration_since(modified).map_or(0, |age| age.as_secs()) {
+                age if age < DAY => 0,
+                age if age < 7 * DAY => 1,
+                age if age < 30 * DAY => 2,
+                _ => 3,
+            },
+            Err(_) => 4,
+        };
+        histogram[bucket].1 += 1;
+    }
+    if histogram[4].1 == 0 {
+        histogram.pop();
+    }
+    histogram
+}
+
+/// Counts the matching files at each depth below their search root, for `--depth-histogram`.
+///
+/// # Parameters
+///
+/// * `files` - A slice of the matching files.
+///
+/// # Returns
+///
+/// Every depth from the shallowest match to the deepest, levels without a match included,
+/// with its count.
+fn depth_histogram(files: &[SearchResult]) -> Vec<(usize, usize)> {
+    let depths: Vec<usize> = files.iter().filter_map(|file| file.depth).collect();
+    let (Some(&shallowest), Some(&deepest)) = (depths.iter().min(), depths.iter().max()) else {
+        return Vec::new();
+    };
+    let mut histogram: Vec<(usize, usize)> = (shallowest..=deepest).map(|depth| (depth, 0)).collect();
+    for depth in depths {
+        histogram[depth - shallowest].1 += 1;
+    }
+    histogram
+}
+
+/// Returns the extension of a matching file as `--by-ext` and `--max-per-ext` compare it:
+/// lowercased with a leading dot, or `(none)`.
+fn extension_key(path: &str) -> String {
+    match Path::new(path).extension() {
+        Some(ext) => format!(".{}", ext.to_string_lossy().to_lowercase()),
+        None => String::from("(none)"),
+    }
+}
+
+/// Returns the directory holding a matching file, `.` for a file given without one.
+fn parent_dir(path: &str) -> String {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_string_lossy().into_owned(),
+        _ => String::from("."),
+    }
+}
+
+/// Counts the matching files per parent directory, for `--count-by-dir`.
+///
+/// Only the immediate parent is credited, so a directory's count doesn't include the
+/// matches in its subdirectories. Files given without a directory are counted under `.`.
+///
+/// # Parameters
+///
+/// * `files` - A slice of the matching files.
+///
+/// # Returns
+///
+/// The directories and their counts, most matches first, ties sorted by path.
+fn directory_histogram(files: &[SearchResult]) -> Vec<(String, usize)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for file in files {
+        *counts.entry(parent_dir(&file.path)).or_default() += 1;
+    }
+
+    let mut histogram: Vec<(String, usize)> = counts.into_iter().collect();
+    histogram.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    histogram
+}
+
+/// Quotes a string for a POSIX shell.
+///
+/// The string is wrapped in single quotes, inside which nothing is special to the
+/// shell; embedded single quotes are written as `'\''`.
+///
+/// # Parameters
+///
+/// * `s` - The string to quote, typically a path.
+///
+/// # Returns
+///
+/// The quoted string, safe to paste into a shell command or `eval`.
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(shell_quote("it's here"), r"'it'\''s here'");
+/// ```
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Finds who last changed each match, or its first matching line, with `git`.
+///
+/// Matches are grouped by work tree, and each work tree is listed once with `git ls-files`,
+/// so that untracked files cost nothing. The matches reported as whole files then share a
+/// single `git log`, read only until the last commit of each of them is known; `git blame`
+/// takes one file at a time, so each matching line is blamed on its own. Whether a directory
+/// is in a work tree is only asked once per directory. Paths outside a work tree, untracked
+/// files and a missing `git` all get no `Blame`.
+///
+/// # Parameters
+///
+/// * `files` - The matches, which get their `blame` filled in.
+fn git_blame_all(files: &mut [SearchResult]) {
+    // Per directory, the top of its work tree and its own path below it
+    let mut work_trees: HashMap<PathBuf, Option<(PathBuf, String)>> = HashMap::new();
+    let mut by_tree: HashMap<PathBuf, Vec<(usize, String)>> = HashMap::new();
+    for (index, file) in files.iter().enumerate() {
+        let path = Path::new(&file.path);
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let work_tree = work_trees.entry(dir.to_path_buf()).or_insert_with(|| {
+            let out = git_output(git_command(dir).args(["rev-parse", "--show-toplevel", "--show-prefix"]))?;
+            let mut lines = out.lines();
+            Some((PathBuf::from(lines.next()?), lines.next().unwrap_or("").to_string()))
+        });
+        if let Some((top, prefix)) = work_tree {
+            by_tree.entry(top.clone()).or_default().push((index, format!("{}{}", prefix, name)));
+        }
+    }
+
+    for (top, matches) in by_tree {
+        let Some(listing) = git_output(git_command(&top).args(["ls-files", "-z"])) else {
+            continue;
+        };
+        let tracked: HashSet<&str> = listing.split('\0').collect();
+        let mut whole_files: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (index, relative) in &matches {
+            if !tracked.contains(relative.as_str()) {
+                continue;
+            }
+            match files[*index].first_line {
+                Some(line) => files[*index].blame = git_blame_line(&top, relative, line),
+                None => whole_files.entry(relative).or_default().push(*index),
+            }
+        }
+        if !whole_files.is_empty() {
+            git_last_commits(&top, whole_files, files);
+        }
+    }
+}
+
+/// Blames a single line of a tracked file with `git blame`.
+///
+/// # Parameters
+///
+/// * `top` - The top directory of the work tree.
+/// * `relative` - The path of the file below `top`.
+/// * `line` - The line to blame, starting at 1.
+///
+/// # Returns
+///
+/// The author and abbreviated hash of the commit, if git knows them.
+fn git_blame_line(top: &Path, relative: &str, line: usize) -> Option<Blame> {
+    let range = format!("{},{}", line, line);
+    let porcelain = git_output(git_command(top).args(["blame", "--porcelain", "-L", &range, "--", relative]))?;
+    let commit = porcelain.split_whitespace().next()?.chars().take(7).collect();
+    let author = porcelain.lines().find_map(|l| l.strip_prefix("author "))?.to_string();
+    Some(Blame { author, commit })
+}
+
+/// Fills in the last commit of tracked files from a single `git log` of their work tree.
+///
+/// The history is read from the newest commit, and `git` is stopped as soon as every file
+/// has been seen, so recently changed files don't cost a walk through the whole history.
+///
+/// # Parameters
+///
+/// * `top` - The top directory of the work tree.
+/// * `wanted` - The matches to annotate, by their path below `top`.
+/// * `files` - The matches the indices in `wanted` refer to.
+fn git_last_commits(top: &Path, mut wanted: HashMap<&str, Vec<usize>>, files: &mut [SearchResult]) {
+    let child = git_command(top)
+        .args(["-c", "core.quotePath=false", "log", "--format=%x00%an%x00%h", "--name-only"])
+        .stdout(std::process::Stdio::piped())
+        .spawn();
+    let Ok(mut child) = child else {
+        return;
+    };
+    if let Some(stdout) = child.stdout.take() {
+        let mut commit: Option<Blame> = None;
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else { break };
+            if let Some(header) = line.strip_prefix('\0') {
+                commit = header.split_once('\0')
+                    .map(|(author, hash)| Blame { author: author.to_string(), commit: hash.to_string() });
+            } else if let (Some(indices), Some(blame)) = (wanted.remove(line.as_str()), &commit) {
+                for index in indices {
+                    files[index].blame = Some(blame.clone());
+                }
+                if wanted.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Prepares a `git` command run in `dir`, with its error output silenced.
+fn git_command(dir: &Path) -> std::process::Command {
+    let mut command = std::process::Command::new("git");
+    command.arg("-C").arg(dir).stderr(std::process::Stdio::null());
+    command
+}
+
+/// Runs a `git` command, returning its output if it succeeded.
+fn git_output(command: &mut std::process::Command) -> Option<String> {
+    command.output().ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Reduces a path to its file name, for `--names-only` and `--stems-only`.
+///
+/// # Parameters
+///
+/// * `path` - The path of a match.
+/// * `stem` - Whether to drop the extension too, as `Path::file_stem` does.
+///
+/// # Returns
+///
+/// The name, or the path unchanged when it has none (such as `/`).
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(file_name_only("src/archive.tar.gz", true), "archive.tar");
+/// ```
+fn file_name_only(path: &str, stem: bool) -> String {
+    let path = Path::new(path);
+    let name = if stem { path.file_stem() } else { path.file_name() };
+    name.map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy()).into_owned()
+}
+
+/// Rewrites a path relative to the current directory, for `--cwd-relative`.
+///
+/// The path is made absolute without resolving symlinks and its `..` components are
+/// folded lexically, then the relative path is computed from the components of both, so
+/// `..` steps out of the current directory.
+///
+/// # Parameters
+///
+/// * `path` - The path of a match, absolute or relative to the current directory.
+/// * `cwd` - A reference to the `Path` of the current directory.
+///
+/// # Returns
+///
+/// The relative path, `.` for the current directory itself, or the absolute path when
+/// there is no relative one (another drive on Windows).
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(cwd_relative("/srv/other/file", Path::new("/srv/project")), "../other/file");
+/// ```
+fn cwd_relative(path: &str, cwd: &Path) -> String {
+    let mut absolute = PathBuf::new();
+    for component in std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path)).components() {
+        match component {
+            std::path::Component::ParentDir => {
+                absolute.pop();
+            }
+            component => absolute.push(component),
+        }
+    }
+    match pathdiff::diff_paths(&absolute, cwd) {
+        Some(relative) if relative.as_os_str().is_empty() => String::from("."),
+        Some(relative) => relative.to_string_lossy().into_owned(),
+        None => absolute.to_string_lossy().into_owned(),
+    }
+}
+
+/// Builds the `file://` URI of a path, for `--uri`.
+///
+/// The path is made absolute by resolving it (or, if it no longer exists, by joining it
+/// to the current directory). Every byte outside the unreserved characters and `/` is
+/// percent-encoded, so spaces become `%20` and non-ASCII names are UTF-8 encoded. On
+/// Windows, `C:\dir` becomes `file:///C:/dir` and `\\server\share` `file://server/share`.
+///
+/// # Parameters
+///
+/// * `path` - A reference to the `Path` of the match.
+///
+/// # Returns
+///
+/// The URI.
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(file_uri(Path::new("/tmp/my notes.txt")), "file:///tmp/my%20notes.txt");
+/// ```
+fn file_uri(path: &Path) -> String {
+    let absolute = fs::canonicalize(path).or_else(|_| std::path::absolute(path)).unwrap_or_else(|_| path.to_path_buf());
 
-    let mut directories: Vec<PathBuf> = args.dir.iter().map(PathBuf::from).collect();
-    directories.extend(args.additional_dirs.clone());
+    #[cfg(unix)]
+    let (host, bytes) = {
+        use std::os::unix::ffi::OsStrExt;
+        ("", absolute.as_os_str().as_bytes().to_vec())
+    };
+    #[cfg(not(unix))]
+    let (host, bytes) = {
+        // Canonical paths are verbatim (`\\?\C:\...`), which no URI can hold
+        let text = absolute.to_string_lossy().replace('\\', "/");
+        let text = text.strip_prefix("//?/").unwrap_or(&text).to_string();
+        match text.strip_prefix("UNC/").or_else(|| text.strip_prefix("//")) {
+            Some(unc) => {
+                let (server, share) = unc.split_once('/').unwrap_or((unc, ""));
+                (server.to_string(), format!("/{}", share).into_bytes())
+            }
+            None if text.starts_with('/') => (String::new(), text.into_bytes()),
+            None => (String::new(), format!("/{}", text).into_bytes()),
+        }
+    };
 
-    // If no directories are specified, use the current directory
-    if directories.is_empty() {
-        directories.push(PathBuf::from("."));
+    let mut uri = format!("file://{}", host);
+    for (i, &byte) in bytes.iter().enumerate() {
+        // A drive letter is followed by a literal colon (`/C:/`)
+        let drive_colon = cfg!(not(unix)) && i == 2 && byte == b':' && bytes[1].is_ascii_alphabetic();
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) || drive_colon {
+            uri.push(char::from(byte));
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
     }
+    uri
+}
 
-    let mut all_files = Vec::new();
-    let mut all_permission_denied_dirs = Vec::new();
-    let mut other_error_occurred = false;
-    let mut error_messages = String::new();
+/// A writer passing through at most `remaining` more bytes, for `--max-output-bytes`.
+///
+/// The write crossing the limit is cut short and followed by the truncation notice; anything
+/// written after it is discarded, while still reported as written so the search carries on.
+struct CappedWriter {
+    inner: Box<dyn Write>,
+    remaining: u64,
+    truncated: bool,
+    /// Whether the last byte passed through ended a line.
+    line_start: bool,
+}
 
-    for dir in &directories {
-        let (files, perm_denied_dirs, other_error, err_msg) = search_files(dir, &args, &filter_regexes);
-        all_files.extend(files);
-        all_permission_denied_dirs.extend(perm_denied_dirs);
-        other_error_occurred |= other_error;
-        if !err_msg.is_empty() {
-            error_messages.push_str(&err_msg);
-            error_messages.push('\n');
+impl Write for CappedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.truncated {
+            return Ok(buf.len());
         }
+        if buf.len() as u64 <= self.remaining {
+            let written = self.inner.write(buf)?;
+            self.remaining -= written as u64;
+            if written > 0 {
+                self.line_start = buf[written - 1] == b'\n';
+            }
+            return Ok(written);
+        }
+        let kept = &buf[..self.remaining as usize];
+        self.inner.write_all(kept)?;
+        self.truncated = true;
+        self.remaining = 0;
+        let line_start = kept.last().map_or(self.line_start, |&last| last == b'\n');
+        let separator = if line_start { "" } else { "\n" };
+        write!(self.inner, "{}{}", separator, "(output truncated)".yellow())?;
+        writeln!(self.inner)?;
+        Ok(buf.len())
     }
 
-    display_results(&args, &directories, all_files, all_permission_denied_dirs, other_error_occurred, error_messages);
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
-/// Searches for files in the specified directory based on given criteria.
+/// Displays the search results and any errors that occurred during the search.
 ///
 /// # Parameters
 ///
-/// * `dir` - A reference to a `Path` representing the directory to search in.
+/// * `out` - The writer results are printed to (stdout or the `--output` file).
 /// * `args` - A reference to `Args` containing the search criteria and options.
-/// * `filter_regexes` - A slice of `Regex` patterns to filter file names.
+/// * `patterns` - A reference to the compiled `Patterns`, holding the `--format-template`.
+/// * `directories` - A slice of `PathBuf` representing the directories searched.
+/// * `files` - A `Vec<SearchResult>` of matching files found.
+/// * `permission_denied_dirs` - A `Vec<String>` of directories where permission was denied.
+/// * `other_error_occurred` - A `bool` indicating if any other errors occurred.
+/// * `error_messages` - A `String` containing any error messages.
+/// * `stats` - A reference to the `SearchStats` printed with `--stats`.
 ///
 /// # Returns
 ///
-/// A tuple containing:
-/// * `Vec<String>` - A list of matching file paths.
-/// * `Vec<String>` - A list of directories where permission was denied.
-/// * `bool` - Indicates if any other errors occurred during the search.
-/// * `String` - Contains error messages, if any.
+/// An `io::Result` reporting whether the results could be written to `out`.
 ///
 /// # Example
 ///
 /// ```
 /// let args = Args { /* ... */ };
-/// let filter_regexes = vec![Regex::new(r"\.txt$").unwrap()];
-/// let (files, denied_dirs, has_errors, error_msg) = search_files(Path::new("/home/user"), &args, &filter_regexes);
+/// let directories = vec![PathBuf::from("/home/user")];
+/// let files = vec![SearchResult { path: String::from("/home/user/file.txt"), matching_lines: None, truncated: false, captures: Vec::new(), spans: Vec::new(), score: None, perm_issues: Vec::new(), diff: None, size: None }];
+/// let permission_denied_dirs = vec![String::from("/root")];
+/// display_results(&mut io::stdout(), &args, &patterns, &directories, files, permission_denied_dirs, false, String::new(), &SearchStats::default())?;
 /// ```
-fn search_files(dir: &Path, args: &Args, filter_regexes: &[Regex]) -> (Vec<String>, Vec<String>, bool, String) {
-    let mut files = Vec::new();
-    let mut permission_denied_dirs = Vec::new();
-    let mut other_error_occurred = false;
-    let mut error_message = String::new();
+#[allow(clippy::too_many_arguments)]
+fn display_results(out: &mut dyn Write, args: &Args, patterns: &Patterns, directories: &[PathBuf], files: Vec<SearchResult>, permission_denied_dirs: Vec<String>, other_error_occurred: bool, error_messages: String, stats: &SearchStats) -> io::Result<()> {
+    let mut error_report = String::new();
 
-    // Check if the path is a directory
-    match dir.metadata() {
-        Ok(metadata) => {
-            if !metadata.is_dir() {
-                other_error_occurred = true;
-                error_message = format!("Error: {} is not a directory", dir.display());
-                return (files, permission_denied_dirs, other_error_occurred, error_message);
-            }
-        },
-        Err(e) => {
-            if e.kind() == io::ErrorKind::PermissionDenied {
-                permission_denied_dirs.push(dir.to_string_lossy().into_owned());
-                return (files, permission_denied_dirs, other_error_occurred, error_message);
+    if args.format == OutputFormat::Json {
+        return write_json_report(out, &files, &permission_denied_dirs, &error_messages, args.max_error_entries,
+            args.total_matches.then(|| total_matches(&files)));
+    }
+    if args.format == OutputFormat::Html {
+        return write_html_report(out, patterns, directories, &files, &permission_denied_dirs, &error_messages);
+    }
+
+    // Machine-readable output: JSON lines were streamed unless they had to be sorted first
+    if matches!(args.format, OutputFormat::Jsonl | OutputFormat::JsonlFull) || args.shell_quote || args.uri || args.only_matching || args.diff || args.no_summary || patterns.template.is_some() {
+        for file in &files {
+            if matches!(args.format, OutputFormat::Jsonl | OutputFormat::JsonlFull) {
+                emit_json_line(out, Path::new(&file.path), file)?;
+            } else if args.diff {
+                write_diff(out, file.diff.as_deref().unwrap_or(""))?;
+            } else if args.only_matching {
+                write_spans(out, patterns, file)?;
+            } else if let Some(parts) = &patterns.template {
+                writeln!(out, "{}", render_template(parts, &file.path, &file.captures))?;
+            } else if args.uri {
+                writeln!(out, "{}", file_uri(Path::new(&file.path)))?;
+            } else if args.no_summary {
+                writeln!(out, "{}", format_result(args, file))?;
             } else {
-                other_error_occurred = true;
-                error_message = format!("Error accessing {}: {}", dir.display(), e);
-                return (files, permission_denied_dirs, other_error_occurred, error_message);
+                writeln!(out, "{}", shell_quote(&file.path))?;
+            }
+        }
+        for dir in permission_denied_dirs {
+            error_report.push_str(&format!("Permission denied: {}\n", dir));
+        }
+        if other_error_occurred {
+            for error in error_messages.lines() {
+                error_report.push_str(&format!("{}\n", error));
             }
         }
+        return write_error_report(out, args, &error_report);
     }
 
-    let exclude_regex = args.exclude.as_ref()
-        .and_then(|pattern| Regex::new(&format!("^{}$", pattern.replace("*", ".*"))).ok());
+    // Without matches there is nothing worth printing besides the errors
+    let silent = args.prune_empty && files.is_empty();
 
-    let read_dir = match fs::read_dir(dir) {
-        Ok(rd) => rd,
-        Err(e) => {
-            if e.kind() == io::ErrorKind::PermissionDenied {
-                permission_denied_dirs.push(dir.to_string_lossy().into_owned());
-                return (files, permission_denied_dirs, other_error_occurred, error_message);
-            } else {
-                other_error_occurred = true;
-                error_message = format!("Error reading directory {}: {}", dir.display(), e);
-                return (files, permission_denied_dirs, other_error_occurred, error_message);
+    if args.parameter_show && !silent {
+        writeln!(out, "\n{}", "Search Parameters:".bold())?;
+        writeln!(out, "  Exclude pattern: {}", args.exclude.as_deref().unwrap_or("None"))?;
+        writeln!(out, "  Exclude applies to: {:?}", args.exclude_type)?;
+        writeln!(out, "  Include hidden files: {}", args.include_hidden_files())?;
+        writeln!(out, "  Descend into hidden directories: {}", args.include_hidden_dirs())?;
+        if args.ignore_vcs {
+            writeln!(out, "  Ignored VCS and build directories: {}", VCS_DIRS.join(", "))?;
+        }
+        
+        if !args.name.is_empty() {
+            writeln!(out, "  Name patterns (--name):")?;
+            for pattern in &args.name {
+                writeln!(out, "    - {}", pattern)?;
             }
         }
-    };
 
-    for entry in read_dir {
-        match entry {
-            Ok(entry) => {
-                let path = entry.path();
-                if path.is_dir() {
-                    let (mut sub_files, mut sub_perm_denied, sub_error, sub_err_msg) = search_files(&path, args, filter_regexes);
-                    files.append(&mut sub_files);
-                    permission_denied_dirs.append(&mut sub_perm_denied);
-                    other_error_occurred |= sub_error;
-                    if !sub_err_msg.is_empty() {
-                        error_message.push_str(&sub_err_msg);
-                        error_message.push('\n');
-                    }
-                } else {
-                    if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
-                        let full_path = path.to_string_lossy().into_owned();
-                        
-                        let name_matches = (args.all || !file_name.starts_with('.')) &&
-                            (filter_regexes.is_empty() || filter_regexes.iter().any(|re| re.is_match(file_name))) &&
-                            exclude_regex.as_ref().map_or(true, |re| !re.is_match(file_name));
-
-                        let content_matches = if args.content {
-                            match search_content(&path, filter_regexes) {
-                                Ok(matches) => matches,
-                                Err(e) => {
-                                    other_error_occurred = true;
-                                    error_message.push_str(&format!("Error reading file {}: {}\n", path.display(), e));
-                                    false
-                                }
-                            }
-                        } else {
-                            false
-                        };
+        // Once --name covers the file names, --filter only applies to the content
+        let filter_label = match (args.content, args.name.is_empty()) {
+            (true, false) => "Content patterns (--filter)",
+            (true, true) => "Name and content patterns (--filter)",
+            (false, _) => "Name patterns (--filter)",
+        };
+        writeln!(out, "  {}:", filter_label)?;
+        if args.filter.is_empty() {
+            writeln!(out, "    None")?;
+        } else {
+            for pattern in &args.filter {
+                writeln!(out, "    - {}", pattern)?;
+            }
+        }
+        
+        if let [pattern, replacement] = args.replace.as_slice() {
+            writeln!(out, "  Replace: {} -> {}", pattern, replacement)?;
+        }
 
-                        if name_matches || content_matches {
-                            files.push(full_path);
-                        }
-                    }
-                }
+        if !args.content_not.is_empty() {
+            writeln!(out, "  Negated content patterns:")?;
+            for pattern in &args.content_not {
+                writeln!(out, "    - {}", pattern)?;
             }
-            Err(e) => {
-                if e.kind() == io::ErrorKind::PermissionDenied {
-                    permission_denied_dirs.push(dir.to_string_lossy().into_owned());
-                } else {
-                    other_error_occurred = true;
-                    error_message.push_str(&format!("Error accessing entry: {}\n", e));
+        }
+
+        if !args.content_exclude.is_empty() {
+            writeln!(out, "  Ignored content lines:")?;
+            for pattern in &args.content_exclude {
+                writeln!(out, "    - {}", pattern)?;
+            }
+        }
+
+        if !args.force_include.is_empty() {
+            writeln!(out, "  Always included (--force-include):")?;
+            for pattern in &args.force_include {
+                writeln!(out, "    - {}", pattern)?;
+            }
+        }
+
+        if let Some(list) = &args.paths_from {
+            writeln!(out, "  Paths read from: {}", list.display())?;
+        } else {
+            writeln!(out, "  Directories searched:")?;
+            for dir in directories {
+                writeln!(out, "    - {}", dir.display())?;
+            }
+        }
+    }
+
+    let files_found = files.len();
+    let histogram = if args.by_ext { extension_histogram(&files) } else { Vec::new() };
+    let ages = if args.age_histogram && !files.is_empty() { age_histogram(&files, SystemTime::now()) } else { Vec::new() };
+    let depths = if args.depth_histogram { depth_histogram(&files) } else { Vec::new() };
+    let total_matches = args.total_matches.then(|| total_matches(&files));
+    let format_total = |(total, truncated): (usize, bool)| format!("{}{}", if truncated { "at least " } else { "" }, total);
+    if !silent {
+        writeln!(out, "\n{}", "Search Results:".bold())?;
+        if files.is_empty() {
+            writeln!(out, "  No files found matching the criteria.")?;
+        } else if args.audit_perms {
+            writeln!(out, "  Found {} file(s) with unsafe permissions:", files.len())?;
+            // A file is listed under every issue it has
+            for issue in PermIssue::ALL {
+                let flagged: Vec<&SearchResult> = files.iter().filter(|file| file.perm_issues.contains(&issue)).collect();
+                if flagged.is_empty() {
+                    continue;
                 }
+                writeln!(out, "\n  {} ({}):", issue.label().yellow().bold(), flagged.len())?;
+                for file in flagged {
+                    writeln!(out, "  - {}", format_result(args, file))?;
+                }
+            }
+        } else if args.count_by_dir {
+            let tallies = directory_histogram(&files);
+            writeln!(out, "  Found {} file(s) in {} director{}:", files.len(), tallies.len(), if tallies.len() == 1 { "y" } else { "ies" })?;
+            let width = tallies.iter().map(|(_, count)| count.to_string().len()).max().unwrap_or(0);
+            for (dir, count) in &tallies {
+                writeln!(out, "  {:>width$}  {}", count, dir, width = width)?;
+            }
+        } else {
+            match stats.sampled_from {
+                Some(total) if total > files.len() => writeln!(out, "  Found {} file(s), a random sample of {} matches:", files.len(), total)?,
+                _ => writeln!(out, "  Found {} file(s):", files.len())?,
+            }
+            for file in files {
+                writeln!(out, "  - {}", format_result(args, &file))?;
+            }
+        }
+    }
+
+    if !stats.duplicate_names.is_empty() {
+        writeln!(out, "\n{}", "Duplicate Names:".bold())?;
+        for (name, first, later) in &stats.duplicate_names {
+            writeln!(out, "  {} (first: {})", name.bold(), first)?;
+            for path in later {
+                writeln!(out, "    - {}", path)?;
             }
         }
     }
 
-    (files, permission_denied_dirs, other_error_occurred, error_message)
+    if let (Some(total), false) = (total_matches, silent || args.stats) {
+        writeln!(out, "\n{} {}", "Total matching lines:".bold(), format_total(total))?;
+    }
+
+    if !histogram.is_empty() {
+        writeln!(out, "\n{}", "Matches by Extension:".bold())?;
+        let width = histogram.iter().map(|(ext, _)| ext.chars().count()).max().unwrap_or(0);
+        for (ext, count) in &histogram {
+            writeln!(out, "  {:<width$}  {}", ext, count, width = width)?;
+        }
+    }
+
+    if !ages.is_empty() {
+        writeln!(out, "\n{}", "Matches by Age:".bold())?;
+        let width = ages.iter().map(|(bucket, _)| bucket.len()).max().unwrap_or(0);
+        for (bucket, count) in &ages {
+            writeln!(out, "  {:<width$}  {}", bucket, count, width = width)?;
+        }
+    }
+
+    if !depths.is_empty() {
+        writeln!(out, "\n{}", "Matches by Depth:".bold())?;
+        let width = depths.iter().map(|(depth, _)| depth.to_string().len()).max().unwrap_or(0);
+        for (depth, count) in &depths {
+            writeln!(out, "  {:>width$}  {}", depth, count, width = width)?;
+        }
+    }
+
+    if !permission_denied_dirs.is_empty() {
+        // The same directory is reported once per unreadable entry, list it only once
+        let mut seen = HashSet::new();
+        let unique_dirs: Vec<&String> = permission_denied_dirs.iter().filter(|dir| seen.insert(*dir)).collect();
+        let shown = match args.max_error_entries {
+            Some(max) => max,
+            None if args.verbose == 0 => PERMISSION_DENIED_PREVIEW,
+            None => unique_dirs.len(),
+        };
+
+        error_report.push_str(&format!("\n{}\n", "Permission Denied:".red().bold()));
+        for dir in unique_dirs.iter().take(shown) {
+            error_report.push_str(&format!("  - {}\n", dir.red()));
+        }
+        if unique_dirs.len() > shown {
+            let hint = if args.max_error_entries.is_some() { "" } else { " (use -v to list all)" };
+            error_report.push_str(&format!("  {}\n", format!("... and {} more{}", unique_dirs.len() - shown, hint).red()));
+        }
+    }
+
+    if other_error_occurred {
+        error_report.push_str(&format!("\n{}\n", "Errors:".red().bold()));
+        for error in error_messages.lines() {
+            error_report.push_str(&format!("  {}\n", error.red()));
+        }
+    }
+
+    // A separate summary comes after everything else
+    let deferred = args.separate_stderr_summary.is_some();
+    if !deferred {
+        write_error_report(out, args, &error_report)?;
+    }
+
+    if silent {
+        return if deferred { write_error_report(out, args, &error_report) } else { Ok(()) };
+    }
+
+    if args.stats {
+        writeln!(out, "\n{}", "Statistics:".bold())?;
+        writeln!(out, "  Directories searched: {}", stats.dirs_searched)?;
+        writeln!(out, "  Files checked: {}", stats.files_checked)?;
+        writeln!(out, "  Matches: {}", files_found)?;
+        if let Some(total) = stats.sampled_from {
+            writeln!(out, "  Sampled from: {} matches", total)?;
+        }
+        if let Some(total) = total_matches {
+            writeln!(out, "  Matching lines: {}", format_total(total))?;
+        }
+        if args.unique || args.skip_seen {
+            writeln!(out, "  Duplicates collapsed: {}", stats.duplicates_collapsed)?;
+        }
+        writeln!(out, "  Elapsed: {} ms", stats.elapsed.as_millis())?;
+        if args.content {
+            writeln!(out, "  Empty files skipped: {}", stats.zero_byte_skipped)?;
+        }
+        if let Some(warm_elapsed) = stats.warm_elapsed {
+            writeln!(out, "  Warm-up pass: {} ms (not included above)", warm_elapsed.as_millis())?;
+        }
+    }
+
+    writeln!(out, "\n{}", "Search completed.".bold())?;
+    if deferred {
+        write_error_report(out, args, &error_report)?;
+    }
+    Ok(())
 }
 
-/// Searches for content within a file based on given regex patterns.
+/// Adds up the matching lines of the results, for `--total-matches`.
 ///
 /// # Parameters
 ///
-/// * `file_path` - A reference to a `Path` representing the file to search in.
-/// * `filter_regexes` - A slice of `Regex` patterns to match against file content.
+/// * `files` - A slice of the matching files.
 ///
 /// # Returns
 ///
-/// A `Result` containing:
-/// * `Ok(bool)` - `true` if any regex pattern matches the file content, `false` otherwise.
-/// * `Err(io::Error)` - If there was an error reading the file.
+/// The total, and whether a count cut short by `--max-matches-per-file` makes it a lower bound.
+fn total_matches(files: &[SearchResult]) -> (usize, bool) {
+    (files.iter().filter_map(|file| file.matching_lines).sum(), files.iter().any(|file| file.truncated))
+}
+
+/// Writes the results as a pretty-printed JSON `SearchReport`.
 ///
-/// # Example
+/// # Parameters
 ///
-/// ```
-/// let filter_regexes = vec![Regex::new(r"important").unwrap()];
-/// match search_content(Path::new("/path/to/file.txt"), &filter_regexes) {
-///     Ok(true) => println!("Content found"),
-///     Ok(false) => println!("Content not found"),
-///     Err(e) => eprintln!("Error searching file: {}", e),
-/// }
-/// ```
-fn search_content(file_path: &Path, filter_regexes: &[Regex]) -> io::Result<bool> {
-    let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
-
-    for line in reader.lines() {
-        let line = line?;
-        if filter_regexes.iter().any(|re| re.is_match(&line)) {
-            return Ok(true);
-        }
-        
+/// * `out` - The writer the report is written to.
+/// * `files` - A slice of the matching files.
+/// * `permission_denied_dirs` - A slice of the directories where permission was denied.
+/// * `error_messages` - The other error messages, one per line.
+/// * `max_error_entries` - How many permission-denied directories to list, if limited.
+/// * `total_matches` - The total from `total_matches`, with `--total-matches`.
+///
+/// # Returns
+///
+/// An `io::Result` indicating whether the report could be written.
+fn write_json_report(out: &mut dyn Write, files: &[SearchResult], permission_denied_dirs: &[String], error_messages: &str, max_error_entries: Option<usize>,
+                     total_matches: Option<(usize, bool)>) -> io::Result<()> {
+    let mut seen = HashSet::new();
+    let mut unique_dirs: Vec<&str> = permission_denied_dirs.iter()
+        .filter(|dir| seen.insert(*dir))
+        .map(String::as_str)
+        .collect();
+    let total = unique_dirs.len();
+    if let Some(max) = max_error_entries {
+        unique_dirs.truncate(max);
     }
+    let report = SearchReport {
+        schema_version: JSON_SCHEMA_VERSION,
+        files: files.iter().map(|file| json_record(Path::new(&file.path), file)).collect(),
+        permission_denied_dirs: unique_dirs,
+        permission_denied_count: max_error_entries.map(|_| total),
+        permission_denied_truncated: max_error_entries.map(|max| total > max),
+        total_matches: total_matches.map(|(total, _)| total),
+        total_matches_truncated: total_matches.map(|(_, truncated)| truncated),
+        error_messages: error_messages.lines().filter(|line| !line.is_empty()).collect(),
+    };
+    let document = serde_json::to_string_pretty(&report).map_err(io::Error::other)?;
+    writeln!(out, "{}", document)
+}
 
-    Ok(false)
+/// Style sheet embedded in the `--format html` report, so that the page needs no other file.
+const HTML_STYLE: &str = "body { font-family: sans-serif; margin: 2em; color: #222; }
+summary { cursor: pointer; font-weight: bold; padding: 0.2em 0; }
+summary .count { color: #777; font-weight: normal; }
+ul { list-style: none; padding-left: 1.5em; margin: 0.3em 0; }
+li { margin: 0.2em 0; font-family: monospace; }
+pre { background: #f4f4f4; margin: 0.2em 0 0.6em; padding: 0.4em; overflow-x: auto; }
+mark { background: #ffe066; }
+.errors li { color: #b00; }";
+
+/// Escapes the characters that are special in HTML text and attribute values.
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
 }
 
-/// Displays the search results and any errors that occurred during the search.
+/// Escapes a content line for the HTML report, wrapping the parts matched by `spans` in `<mark>`.
 ///
 /// # Parameters
 ///
-/// * `args` - A reference to `Args` containing the search criteria and options.
-/// * `directories` - A slice of `PathBuf` representing the directories searched.
-/// * `files` - A `Vec<String>` of matching file paths found.
-/// * `permission_denied_dirs` - A `Vec<String>` of directories where permission was denied.
-/// * `other_error_occurred` - A `bool` indicating if any other errors occurred.
-/// * `error_messages` - A `String` containing any error messages.
+/// * `line` - The matching line.
+/// * `spans` - The span patterns from `span_regex`.
 ///
 /// # Returns
 ///
-/// This function doesn't return a value; it prints the results to the console.
+/// The escaped HTML.
+fn highlight_html(line: &str, spans: &[Regex]) -> String {
+    let mut ranges: Vec<(usize, usize)> = spans.iter()
+        .flat_map(|re| re.find_iter(line).filter(|m| !m.is_empty()).map(|m| (m.start(), m.end())))
+        .collect();
+    ranges.sort_unstable();
+
+    let mut html = String::new();
+    let mut position = 0;
+    for (start, end) in ranges {
+        // Overlapping matches are merged into the first one
+        if end <= position {
+            continue;
+        }
+        let start = start.max(position);
+        html.push_str(&html_escape(&line[position..start]));
+        html.push_str(&format!("<mark>{}</mark>", html_escape(&line[start..end])));
+        position = end;
+    }
+    html.push_str(&html_escape(&line[position..]));
+    html
+}
+
+/// Writes the results as a standalone HTML page, for `--format html`.
 ///
-/// # Example
+/// Matches are grouped in a collapsible section per directory, each file linking to its
+/// `file://` URI and followed by its first matching line in content searches, with the
+/// matched text highlighted.
 ///
-/// ```
-/// let args = Args { /* ... */ };
-/// let directories = vec![PathBuf::from("/home/user")];
-/// let files = vec![String::from("/home/user/file.txt")];
-/// let permission_denied_dirs = vec![String::from("/root")];
-/// display_results(&args, &directories, files, permission_denied_dirs, false, String::new());
-/// ```
-fn display_results(args: &Args, directories: &[PathBuf], files: Vec<String>, permission_denied_dirs: Vec<String>, other_error_occurred: bool, error_messages: String) {
-    if args.parameter_show {
-        println!("\n{}", "Search Parameters:".bold());
-        println!("  Exclude pattern: {}", args.exclude.as_deref().unwrap_or("None"));
-        println!("  Include hidden files: {}", args.all);
-        
-        println!("  Filter patterns:");
-        if args.filter.is_empty() {
-            println!("    None");
-        } else {
-            for pattern in &args.filter {
-                println!("    - {}", pattern);
-            }
+/// # Parameters
+///
+/// * `out` - The writer the page is written to.
+/// * `patterns` - A reference to the compiled `Patterns`, used to highlight the matches.
+/// * `directories` - A slice of `PathBuf` representing the directories searched.
+/// * `files` - A slice of the matching files.
+/// * `permission_denied_dirs` - A slice of the directories where permission was denied.
+/// * `error_messages` - The other error messages, one per line.
+///
+/// # Returns
+///
+/// An `io::Result` indicating whether the page could be written.
+fn write_html_report(out: &mut dyn Write, patterns: &Patterns, directories: &[PathBuf], files: &[SearchResult], permission_denied_dirs: &[String], error_messages: &str) -> io::Result<()> {
+    let roots: Vec<String> = directories.iter().map(|dir| format!("<code>{}</code>", html_escape(&dir.to_string_lossy()))).collect();
+    let mut groups: Vec<(String, Vec<&SearchResult>)> = Vec::new();
+    for file in files {
+        let dir = parent_dir(&file.path);
+        match groups.iter_mut().find(|(name, _)| *name == dir) {
+            Some((_, members)) => members.push(file),
+            None => groups.push((dir, vec![file])),
         }
-        
-        println!("  Directories searched:");
-        for dir in directories {
-            println!("    - {}", dir.display());
+    }
+    groups.sort_by(|a, b| a.0.cmp(&b.0));
+
+    writeln!(out, "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>find_file results</title>\n<style>\n{}\n</style>\n</head>\n<body>", HTML_STYLE)?;
+    let searched = if roots.is_empty() { String::new() } else { format!(", searched in {}", roots.join(", ")) };
+    writeln!(out, "<h1>Search results</h1>\n<p>{} file(s) in {} director{}{}.</p>",
+        files.len(), groups.len(), if groups.len() == 1 { "y" } else { "ies" }, searched)?;
+
+    for (dir, members) in &groups {
+        writeln!(out, "<details open>\n<summary>{} <span class=\"count\">({})</span></summary>\n<ul>", html_escape(dir), members.len())?;
+        for file in members {
+            let name = Path::new(&file.path).file_name().map_or_else(|| file.path.clone(), |n| n.to_string_lossy().into_owned());
+            writeln!(out, "<li><a href=\"{}\" title=\"{}\">{}</a>", html_escape(&file_uri(Path::new(&file.path))), html_escape(&file.path), html_escape(&name))?;
+            if let Some(Some(line)) = file.captures.first() {
+                writeln!(out, "<pre>{}</pre>", highlight_html(line, &patterns.spans))?;
+            }
+            writeln!(out, "</li>")?;
         }
+        writeln!(out, "</ul>\n</details>")?;
     }
 
-    println!("\n{}", "Search Results:".bold());
-    if files.is_empty() {
-        println!("  No files found matching the criteria.");
-    } else {
-        println!("  Found {} file(s):", files.len());
-        for file in files {
-            println!("  - {}", file);
+    let mut seen = HashSet::new();
+    let errors: Vec<String> = permission_denied_dirs.iter()
+        .filter(|dir| seen.insert(*dir))
+        .map(|dir| format!("Permission denied: {}", dir))
+        .chain(error_messages.lines().filter(|line| !line.is_empty()).map(String::from))
+        .collect();
+    if !errors.is_empty() {
+        writeln!(out, "<h2>Errors</h2>\n<ul class=\"errors\">")?;
+        for error in &errors {
+            writeln!(out, "<li>{}</li>", html_escape(error))?;
         }
+        writeln!(out, "</ul>")?;
     }
+    writeln!(out, "</body>\n</html>")
+}
 
-    if !permission_denied_dirs.is_empty() {
-        eprintln!("\n{}", "Permission Denied:".red().bold());
-        for dir in permission_denied_dirs {
-            eprintln!("  - {}", dir.red());
+/// Writes the error report where it was asked to go.
+///
+/// Errors go to stderr, unless `--output-errors` sends them along with the results or
+/// `--separate-stderr-summary` sends them to a file (without color codes).
+/// `--separate-stderr-summary` without a file prints them on stderr behind a `---` line,
+/// once the results have been flushed.
+///
+/// # Parameters
+///
+/// * `out` - The writer results are printed to (stdout or the `--output` file).
+/// * `args` - A reference to `Args` containing the output options.
+/// * `report` - The error report, possibly empty.
+///
+/// # Returns
+///
+/// An `io::Result` indicating whether the report could be written.
+fn write_error_report(out: &mut dyn Write, args: &Args, report: &str) -> io::Result<()> {
+    if args.output.is_some() && args.output_errors {
+        return out.write_all(report.as_bytes());
+    }
+    match &args.separate_stderr_summary {
+        Some(Some(path)) => {
+            let escapes = Regex::new("\x1b\\[[0-9;]*m").expect("valid escape code regex");
+            fs::write(path, escapes.replace_all(report, "").as_bytes())
+        }
+        Some(None) if !report.is_empty() => {
+            out.flush()?;
+            eprintln!("---");
+            eprint!("{}", report.trim_start_matches('\n'));
+            Ok(())
+        }
+        _ => {
+            eprint!("{}", report);
+            Ok(())
         }
     }
+}
 
-    if other_error_occurred {
-        eprintln!("\n{}", "Errors:".red().bold());
-        for error in error_messages.lines() {
-            eprintln!("  {}", error.red());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_quote_wraps_plain_paths() {
+        assert_eq!(shell_quote("src/main.rs"), "'src/main.rs'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn shell_quote_keeps_spaces_inside_quotes() {
+        assert_eq!(shell_quote("my documents/notes.txt"), "'my documents/notes.txt'");
+    }
+
+    #[test]
+    fn shell_quote_escapes_single_quotes() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("''"), r"''\'''\'''");
+    }
+
+    #[test]
+    fn shell_quote_leaves_dollar_and_backticks_literal() {
+        assert_eq!(shell_quote("$HOME/`id`/$(rm -rf x)"), "'$HOME/`id`/$(rm -rf x)'");
+    }
+
+    /// Times a content search over a few megabytes with several read buffer sizes.
+    /// Run with `cargo test --release read_buffer_size -- --nocapture` to see the timings.
+    #[test]
+    fn read_buffer_size_benchmark() {
+        let path = std::env::temp_dir().join(format!("find_file_buffer_bench_{}.txt", std::process::id()));
+        let line = "the quick brown fox jumps over the lazy dog\n";
+        fs::write(&path, line.repeat(100_000) + "needle\n").unwrap();
+        let filter = [glob_regex("needle", false).unwrap()];
+
+        for buffer_size in [None, Some(512), Some(64 * 1024), Some(1024 * 1024)] {
+            let started = Instant::now();
+            let content = search_content(&path, &filter, &[], &[], false, false, None, false, false, false, buffer_size, None, &[]).unwrap();
+            println!("buffer {:>8}: {:?}", buffer_size.map_or(String::from("default"), |size| size.to_string()), started.elapsed());
+            assert!(content.matched);
         }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Searches `root` with the given options, returning the matches in the order they were found.
+    fn search_tree(root: &Path, options: &[&str]) -> Vec<PathBuf> {
+        let args = Args::try_parse_from(std::iter::once("find_file").chain(options.iter().copied())).unwrap();
+        let patterns = Patterns::from_args(&args).unwrap();
+        let (results, events) = mpsc::sync_channel(RESULT_CHANNEL_CAPACITY);
+        search_root(0, root, &results, None, None, None, &args, &patterns);
+        drop(results);
+        events.into_iter().filter_map(|event| match event {
+            SearchEvent::Match(_, result) => Some(PathBuf::from(result.path)),
+            _ => None,
+        }).collect()
     }
 
-    println!("\n{}", "Search completed.".bold());
+    #[test]
+    #[cfg(unix)]
+    fn follow_only_top_skips_nested_directory_links() {
+        let root = std::env::temp_dir().join(format!("find_file_follow_{}", std::process::id()));
+        fs::create_dir_all(root.join("real")).unwrap();
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("real/f.txt"), "").unwrap();
+        std::os::unix::fs::symlink("../real", root.join("sub/link")).unwrap();
+
+        let mut followed = search_tree(&root, &["-f", "f.txt"]);
+        followed.sort();
+        assert_eq!(followed, [root.join("real/f.txt"), root.join("sub/link/f.txt")]);
+        assert_eq!(search_tree(&root, &["-f", "f.txt", "--follow-only-top"]), [root.join("real/f.txt")]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn depth_reports_directories_after_their_contents() {
+        let root = std::env::temp_dir().join(format!("find_file_depth_{}", std::process::id()));
+        fs::create_dir_all(root.join("x/y")).unwrap();
+        fs::write(root.join("x/m.txt"), "").unwrap();
+        fs::write(root.join("x/y/m.txt"), "").unwrap();
+        let found = search_tree(&root, &["--depth", "--contains-file", "m.txt", "-f", "*"]);
+        assert_eq!(found, [root.join("x/y"), root.join("x")]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn content_not_requires_content() {
+        let error = Args::try_parse_from(["find_file", "--name", "*.rs", "--content-not", "TODO"]).unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+        assert!(Args::try_parse_from(["find_file", "-c", "--content-not", "TODO"]).is_ok());
+    }
+
+    #[test]
+    fn exclude_expands_braces() {
+        let args = Args::try_parse_from(["find_file", "-e", "*.{log,tmp}"]).unwrap();
+        let patterns = Patterns::from_args(&args).unwrap();
+        assert!(!file_name_allowed("debug.log", &args, &patterns));
+        assert!(!file_name_allowed("scratch.tmp", &args, &patterns));
+        assert!(file_name_allowed("main.rs", &args, &patterns));
+    }
+
+    #[test]
+    fn contains_file_expands_braces() {
+        let args = Args::try_parse_from(["find_file", "--contains-file", "*.{rs,log}"]).unwrap();
+        let wanted = Patterns::from_args(&args).unwrap().contains_file.unwrap();
+        assert!(wanted.is_match("main.rs"));
+        assert!(wanted.is_match("debug.log"));
+        assert!(!wanted.is_match("notes.txt"));
+        assert!(!wanted.is_match("main.rs.txt"));
+    }
+
+    #[test]
+    fn name_glob_backslash_escapes_braces() {
+        let args = Args::try_parse_from(["find_file", "--name-glob", r"\{a,b}.txt"]).unwrap();
+        let patterns = Patterns::from_args(&args).unwrap();
+        assert_eq!(patterns.name.len(), 1);
+        assert!(patterns.name[0].is_match("{a,b}.txt"));
+        assert!(!patterns.name[0].is_match("a.txt"));
+    }
+
+    #[test]
+    fn shell_quote_round_trips_through_sh() {
+        let path = "a b/it's $HOME \"x\" \\n";
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", shell_quote(path)))
+            .output()
+            .expect("sh should be available");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), path);
+    }
 }